@@ -0,0 +1,48 @@
+//! Throughput of every dithering algorithm on frames of a 10.3" panel (1872x1404 pixels).
+//!
+//! Run with `cargo bench --no-default-features`, the video player is not needed for this.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use it8951::dither::{BitDepth, DitherMethod};
+
+const WIDTH: u32 = 1872;
+const HEIGHT: u32 = 1404;
+
+/// Gradient with some noise, so every algorithm has to do real work.
+fn gray_frame() -> Vec<u8> {
+    let (width, height) = (WIDTH as usize, HEIGHT as usize);
+    let mut seed: u32 = 1;
+
+    (0..width * height)
+        .map(|index| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let (x, y) = (index % width, index / width);
+            ((x + y) * 255 / (width + height)) as u8 ^ (seed >> 28) as u8
+        })
+        .collect()
+}
+
+fn dither(criterion: &mut Criterion) {
+    let frame = gray_frame();
+
+    for depth in [BitDepth::One, BitDepth::Four] {
+        let mut group = criterion.benchmark_group(format!("dither {}bpp", depth.bits()));
+        group.throughput(Throughput::Elements(1));
+        group.sample_size(20);
+
+        for name in DitherMethod::VARIANTS {
+            let method: DitherMethod = name.parse().expect("variant is valid");
+            let dither = method.create(depth);
+
+            group.bench_function(BenchmarkId::from_parameter(name), |bencher| {
+                bencher.iter(|| dither.dither(&frame, WIDTH, HEIGHT, WIDTH as usize))
+            });
+        }
+
+        group.finish();
+    }
+}
+
+criterion_group!(benches, dither);
+criterion_main!(benches);
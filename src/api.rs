@@ -1,32 +1,59 @@
+//! Commands to upload images to the IT8951 controller and display them on the panel.
+
 use std::fmt;
 use std::str;
-use std::time::Duration;
 
 use bincode::config::Options;
-use rusb::open_device_with_vid_pid;
 use serde::{Deserialize, Serialize};
 
-use crate::usb::ScsiOverUsbConnection;
-
-/// SCSI via USB parameter.
-const ENDPOINT_IN: u8 = 0x81;
-const ENDPOINT_OUT: u8 = 0x02;
-const SCSI_TIMEOUT_MS: u64 = 1000;
+use crate::error::{Error, Result};
+use crate::transport::Transport;
+use crate::usb::{DeviceSelector, ScsiOverUsbConnection, UsbDevice};
 
 /// Customer command.
-const CUSTOMER_CMD: u8 = 0xfe;
+pub(crate) const CUSTOMER_CMD: u8 = 0xfe;
+
+/// Get system information command.
+pub(crate) const GET_SYS_INFO_CMD: u8 = 0x80;
 
 /// Read from register command.
-const READ_REG_CMD: u8 = 0x83;
+pub(crate) const READ_REG_CMD: u8 = 0x83;
 
 /// Write to register command.
-const WRITE_REG_CMD: u8 = 0x84;
+pub(crate) const WRITE_REG_CMD: u8 = 0x84;
+
+/// Display area command.
+pub(crate) const DISPLAY_AREA_CMD: u8 = 0x94;
 
 /// PMIC (Power Management Integrated Circuits) command.
-const PMIC_CONTROL_CMD: u8 = 0xa3;
+pub(crate) const PMIC_CONTROL_CMD: u8 = 0xa3;
+
+/// Write to memory in fast mode command.
+pub(crate) const FAST_WRITE_CMD: u8 = 0xa5;
+
+/// Maximum number of bytes the controller accepts in one fast-write transfer.
+const MAX_TRANSFER: usize = 60 * 1024;
+
+/// Update parameter register, containing the flags for 1bpp and image pitch mode.
+pub const UP1SR_REG: u32 = 0x1800_1138;
+
+/// Flag in the update parameter register enabling 1bpp mode.
+pub const UP1SR_1BPP_FLAG: u32 = 1 << 18;
 
-// Write to memory in fast mode command.
-const FAST_WRITE_CMD: u8 = 0xa5;
+/// Flag in the update parameter register enabling image pitch mode.
+pub const UP1SR_PITCH_FLAG: u32 = 1 << 17;
+
+/// Pixel alignment of the horizontal position of areas displayed in 1bpp mode (whole bytes).
+pub const ONE_BIT_X_ALIGNMENT: u32 = 8;
+
+/// Pixel alignment of the width of areas displayed in 1bpp mode (whole 32bit words).
+pub const ONE_BIT_WIDTH_ALIGNMENT: u32 = 32;
+
+/// Image pitch width register (in 32bit words).
+pub const PITCH_REG: u32 = 0x1800_124c;
+
+/// Bitmap (1bpp) mode color definition register.
+pub const BGVR_REG: u32 = 0x1800_1250;
 
 /// Command to retreive system information.
 const GET_SYS_CMD: [u8; 16] = [
@@ -36,7 +63,7 @@ const GET_SYS_CMD: [u8; 16] = [
     0x39, // Signature[1]
     0x35, // Signature[2]
     0x31, // Signature[3]
-    0x80, // Get System information command
+    GET_SYS_INFO_CMD,
     0x00, // Version[0]: 0x00010002
     0x01, // Version[1]
     0x00, // Version[2]
@@ -56,7 +83,7 @@ const DPY_AREA_CMD: [u8; 16] = [
     0x00,
     0x00,
     0x00,
-    0x94, // Display area command
+    DISPLAY_AREA_CMD, // Display area command
     0x00,
     0x00,
     0x00,
@@ -70,7 +97,7 @@ const DPY_AREA_CMD: [u8; 16] = [
 
 /// Display modes.
 #[repr(u32)]
-#[derive(Serialize, PartialEq, Debug)]
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
 pub enum Mode {
     /// The initialization (INIT) mode is used to completely erase the display and leave it in the
     /// white state.
@@ -119,7 +146,7 @@ pub enum Mode {
 }
 
 impl<'de> Deserialize<'de> for Mode {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
@@ -160,13 +187,13 @@ impl fmt::Display for Mode {
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct SystemInfo {
     /// Standard command number2T-con Communication Protocol.
-    standard_cmd_no: u32,
+    pub standard_cmd_no: u32,
 
     /// Extend command number.
-    extended_cmd_no: u32,
+    pub extended_cmd_no: u32,
 
     /// 31 35 39 38 (8951).
-    signature: u32,
+    pub signature: u32,
 
     /// Command table version.
     pub version: u32,
@@ -184,50 +211,85 @@ pub struct SystemInfo {
     pub image_buffer_base: u32,
 
     /// Temperature segment number.
-    temperature_no: u32,
+    pub temperature_no: u32,
 
     /// Display mode number.
-    mode: Mode,
+    pub mode: Mode,
 
     /// Frame count for each mode(8).
-    frame_count: [u32; 8],
+    pub frame_count: [u32; 8],
 
     /// Numbers of Image buffer.
-    num_img_buf: u32,
+    pub num_img_buf: u32,
 
     /// Don’t care.
-    reserved: [u32; 9],
+    pub(crate) reserved: [u32; 9],
+}
+
+impl SystemInfo {
+    /// Ask the controller about its system configuration.
+    pub fn read<T: Transport>(connection: &mut T) -> Result<Self> {
+        connection.read_command(&GET_SYS_CMD, bincode::options().with_big_endian())
+    }
 }
 
 #[repr(C)]
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
-struct DisplayArea {
+pub(crate) struct DisplayArea {
     /// Memory address to load image buffer from.
-    address: u32,
+    pub(crate) address: u32,
 
     /// E-panel display mode.
-    display_mode: Mode,
+    pub(crate) display_mode: Mode,
 
     /// Display from top position x.
-    x: u32,
+    pub(crate) x: u32,
 
     /// Display from left position y.
-    y: u32,
+    pub(crate) y: u32,
 
     /// Width of data to be displayed.
-    width: u32,
+    pub(crate) width: u32,
 
     /// Height of data to be displayed.
-    height: u32,
+    pub(crate) height: u32,
 
     /// Waiting time before signalling being ready.
-    wait_ready: u32,
+    pub(crate) wait_ready: u32,
+}
+
+/// Rectangle on the e-paper panel.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Area {
+    /// Left position of the area.
+    pub x: u32,
+
+    /// Top position of the area.
+    pub y: u32,
+
+    /// Width of the area.
+    pub width: u32,
+
+    /// Height of the area.
+    pub height: u32,
 }
 
-/// Talk to the IT8951 e-paper display via a USB connection.
-pub struct API {
-    /// SCSI over USB Device handler.
-    connection: ScsiOverUsbConnection,
+impl Area {
+    /// Rectangle at the given position with the given size.
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+}
+
+/// Talk to the IT8951 e-paper display via a USB connection (or any other transport).
+pub struct API<T: Transport = ScsiOverUsbConnection> {
+    /// SCSI Device handler.
+    connection: T,
 
     /// System information from IT8951.
     system_info: SystemInfo,
@@ -237,46 +299,63 @@ pub struct API {
 
     /// Target image height.
     height: u32,
+
+    /// Original values of all registers we changed, in the order they were changed first.
+    register_snapshot: Vec<(u32, u32)>,
+
+    /// Controller is reading images in 1bpp mode.
+    one_bit_mode: bool,
 }
 
-impl Drop for API {
+impl<T: Transport> Drop for API<T> {
     fn drop(&mut self) {
-        self.connection
-            .device_handle
-            .release_interface(0)
-            .expect("release failed");
+        // Leave the controller in the state we found it, other tools might rely on it
+        if let Err(error) = self.restore_registers() {
+            eprintln!("could not restore register values: {}", error);
+        }
     }
 }
 
 impl API {
     /// Establish a connection to the e-paper display via the USB port.
-    pub fn connect(width: u32, height: u32) -> rusb::Result<Self> {
-        // Get USB device handle based on vendor ID and product ID. Make sure you have these values
-        // whitelisted in your OS configuration aka /etc/udev/rules.d
-        let mut device_handle =
-            open_device_with_vid_pid(0x48d, 0x08951).expect("Cannot open it8951");
-        if let Err(e) = device_handle.set_auto_detach_kernel_driver(true) {
-            println!("auto detached failed, error is {}", e);
-        }
-        device_handle.claim_interface(0)?;
+    pub fn connect(width: u32, height: u32) -> Result<Self> {
+        Self::connect_device(&UsbDevice::find(&DeviceSelector::Any)?, width, height)
+    }
 
-        let mut connection = ScsiOverUsbConnection {
-            device_handle,
-            endpoint_out: ENDPOINT_OUT,
-            endpoint_in: ENDPOINT_IN,
-            timeout: Duration::from_millis(SCSI_TIMEOUT_MS),
-        };
+    /// Establish a connection to the given e-paper display. Every display can be connected to
+    /// once, so multiple displays can be used at the same time.
+    pub fn connect_device(device: &UsbDevice, width: u32, height: u32) -> Result<Self> {
+        Self::new(ScsiOverUsbConnection::open(device)?, width, height)
+    }
+}
 
+impl<T: Transport> API<T> {
+    /// Talk to the e-paper display via an already established connection.
+    pub fn new(mut connection: T, width: u32, height: u32) -> Result<Self> {
         // Send first command to device to retreive its system configuration
-        let system_info =
-            connection.read_command(&GET_SYS_CMD, bincode::options().with_big_endian())?;
+        let system_info = SystemInfo::read(&mut connection)?;
+
+        // Make sure the target sizes fit on the display
+        if width == 0 || height == 0 || width > system_info.width || height > system_info.height {
+            return Err(Error::InvalidGeometry(format!(
+                "image of {}x{} does not fit onto panel of {}x{}",
+                width, height, system_info.width, system_info.height
+            )));
+        }
 
-        Ok(Self {
+        let mut api = Self {
             connection,
             system_info,
             width,
             height,
-        })
+            register_snapshot: Vec::new(),
+            one_bit_mode: false,
+        };
+
+        // Find out if the controller is still in 1bpp mode from a previous session
+        api.one_bit_mode = api.get_memory_register_value(UP1SR_REG)? & UP1SR_1BPP_FLAG != 0;
+
+        Ok(api)
     }
 
     /// Return system info about e-paper display.
@@ -285,7 +364,7 @@ impl API {
     }
 
     /// Read value from memory register of controller.
-    pub fn get_memory_register_value(&mut self, address: u32) -> rusb::Result<u32> {
+    pub fn get_memory_register_value(&mut self, address: u32) -> Result<u32> {
         let address_8 = address.to_be_bytes();
 
         let command = [
@@ -315,7 +394,35 @@ impl API {
     }
 
     /// Set memory register value of controller.
-    pub fn set_memory_register_value(&mut self, address: u32, data: u32) -> rusb::Result<()> {
+    ///
+    /// The original value of the register is remembered and restored when the API gets dropped.
+    pub fn set_memory_register_value(&mut self, address: u32, data: u32) -> Result<()> {
+        if !self
+            .register_snapshot
+            .iter()
+            .any(|(snapshot_address, _)| *snapshot_address == address)
+        {
+            let original = self.get_memory_register_value(address)?;
+            self.register_snapshot.push((address, original));
+        }
+
+        self.write_register(address, data)
+    }
+
+    /// Restore original values of all registers which have been changed.
+    pub fn restore_registers(&mut self) -> Result<()> {
+        while let Some((address, original)) = self.register_snapshot.pop() {
+            self.write_register(address, original)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_register(&mut self, address: u32, data: u32) -> Result<()> {
+        if address == UP1SR_REG {
+            self.one_bit_mode = data & UP1SR_1BPP_FLAG != 0;
+        }
+
         let address_8 = address.to_be_bytes();
 
         let command = [
@@ -342,7 +449,7 @@ impl API {
     }
 
     /// Set VCOM value of controller.
-    pub fn set_vcom(&mut self, vcom: f32) -> rusb::Result<()> {
+    pub fn set_vcom(&mut self, vcom: f32) -> Result<()> {
         // For example: -1.58 gets converted to 1580
         let converted = (vcom.abs() * 1000.0) as u16;
         let [vcom_h, vcom_l] = converted.to_be_bytes();
@@ -370,9 +477,38 @@ impl API {
     }
 
     /// Write any data to memory using fast-write mode.
-    pub fn set_memory(&mut self, address: u32, data: &[u8]) -> rusb::Result<()> {
+    ///
+    /// Larger payloads get split into multiple transfers, each one written to the address
+    /// following the previous chunk.
+    pub fn set_memory(&mut self, address: u32, data: &[u8]) -> Result<()> {
+        // Make sure the last byte of the payload fits into the 32bit address space of the
+        // controller
+        let overflow = || Error::MemoryOverflow {
+            address,
+            length: data.len(),
+        };
+        if let Some(last) = data.len().checked_sub(1) {
+            let last: u32 = last.try_into().map_err(|_| overflow())?;
+            address.checked_add(last).ok_or_else(overflow)?;
+        }
+
+        for (index, chunk) in data.chunks(MAX_TRANSFER).enumerate() {
+            self.fast_write(address + (index * MAX_TRANSFER) as u32, chunk)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write a single chunk of data to memory using fast-write mode.
+    fn fast_write(&mut self, address: u32, data: &[u8]) -> Result<()> {
+        // Length of transfer is encoded in two bytes
+        let data_len: u16 = data.len().try_into().map_err(|_| Error::MemoryOverflow {
+            address,
+            length: data.len(),
+        })?;
+
         let address_8 = address.to_be_bytes();
-        let data_len_8 = (data.len() as u16).to_be_bytes();
+        let data_len_8 = data_len.to_be_bytes();
 
         let command = [
             CUSTOMER_CMD,
@@ -393,23 +529,91 @@ impl API {
             0x00,
         ];
 
-        self.connection.write_command_raw(&command, &data)
+        self.connection.write_command_raw(&command, data)
     }
 
     /// Display the centered image on e-panel with a given mode, loading it from the image buffer
     /// in memory.
-    pub fn display_image(&mut self, address: u32, mode: Mode) -> rusb::Result<()> {
+    pub fn display_image(&mut self, address: u32, mode: Mode) -> Result<()> {
+        self.display_area(address, self.centered_area(), mode)
+    }
+
+    /// Area of the panel the image of the target size is displayed in when it is centered.
+    ///
+    /// In 1bpp mode the horizontal position is rounded down to the next multiple of 8 pixels, so
+    /// the image might be slightly left of the center.
+    pub fn centered_area(&self) -> Area {
+        let mut x = (self.system_info.width - self.width) / 2;
+        if self.one_bit_mode {
+            x -= x % ONE_BIT_X_ALIGNMENT;
+        }
+
+        Area::new(
+            x,
+            (self.system_info.height - self.height) / 2,
+            self.width,
+            self.height,
+        )
+    }
+
+    /// Make sure images can be displayed on the given area of the e-panel.
+    ///
+    /// In 1bpp mode the horizontal position of the area needs to be a multiple of 8 pixels and its
+    /// width a multiple of 32 pixels.
+    pub fn check_area(&self, area: Area) -> Result<()> {
         let system_info = self.get_system_info();
 
+        // Make sure the area is on the panel
+        if area.width == 0
+            || area.height == 0
+            || area
+                .x
+                .checked_add(area.width)
+                .is_none_or(|right| right > system_info.width)
+            || area
+                .y
+                .checked_add(area.height)
+                .is_none_or(|bottom| bottom > system_info.height)
+        {
+            return Err(Error::InvalidGeometry(format!(
+                "area of {}x{} at {},{} does not fit onto panel of {}x{}",
+                area.width, area.height, area.x, area.y, system_info.width, system_info.height
+            )));
+        }
+
+        // Every row of 1bpp images starts at a whole byte and is read in 32bit words
+        if self.one_bit_mode
+            && (area.x % ONE_BIT_X_ALIGNMENT != 0 || area.width % ONE_BIT_WIDTH_ALIGNMENT != 0)
+        {
+            return Err(Error::InvalidGeometry(format!(
+                "in 1bpp mode the position of an area needs to be a multiple of {} and its width \
+                a multiple of {}, got {}x{} at {},{}",
+                ONE_BIT_X_ALIGNMENT,
+                ONE_BIT_WIDTH_ALIGNMENT,
+                area.width,
+                area.height,
+                area.x,
+                area.y
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Display an image on the given area of the e-panel with a given mode, loading it from the
+    /// image buffer in memory. The area needs to pass [`API::check_area`].
+    pub fn display_area(&mut self, address: u32, area: Area, mode: Mode) -> Result<()> {
+        self.check_area(area)?;
+
         self.connection.write_command(
             &DPY_AREA_CMD,
             DisplayArea {
                 address,
                 display_mode: mode,
-                x: (system_info.width - self.width) / 2,
-                y: (system_info.height - self.height) / 2,
-                width: self.width,
-                height: self.height,
+                x: area.x,
+                y: area.y,
+                width: area.width,
+                height: area.height,
                 wait_ready: 1,
             },
             &[],
@@ -420,7 +624,7 @@ impl API {
     }
 
     /// Clear the screen by making it completly white.
-    pub fn clear_display(&mut self) -> rusb::Result<()> {
+    pub fn clear_display(&mut self) -> Result<()> {
         let system_info = self.get_system_info();
 
         self.connection.write_command(
@@ -441,3 +645,155 @@ impl API {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockDevice;
+
+    /// Passes commands on to the simulated controller and remembers the address and length of
+    /// every fast-write transfer.
+    struct TransferLog {
+        device: MockDevice,
+        transfers: Vec<(u32, usize)>,
+    }
+
+    impl Transport for TransferLog {
+        fn read_command_raw(&mut self, command: &[u8; 16], length: usize) -> Result<Vec<u8>> {
+            self.device.read_command_raw(command, length)
+        }
+
+        fn write_command_raw(&mut self, command: &[u8; 16], data: &[u8]) -> Result<()> {
+            if command[6] == FAST_WRITE_CMD {
+                let address = u32::from_be_bytes([command[2], command[3], command[4], command[5]]);
+                self.transfers.push((address, data.len()));
+            }
+
+            self.device.write_command_raw(command, data)
+        }
+    }
+
+    /// Panel of 512x256 pixels, its image buffer has space for more than two full transfers.
+    fn connect() -> (MockDevice, API<TransferLog>) {
+        let device = MockDevice::new(512, 256);
+        let log = TransferLog {
+            device: device.clone(),
+            transfers: Vec::new(),
+        };
+
+        (device, API::new(log, 512, 256).unwrap())
+    }
+
+    #[test]
+    fn splits_large_writes_into_transfers() {
+        let (device, mut api) = connect();
+        let address = api.get_system_info().image_buffer_base + 1;
+        let data: Vec<u8> = (0..2 * MAX_TRANSFER + 1001)
+            .map(|index| index as u8)
+            .collect();
+
+        api.set_memory(address, &data).unwrap();
+
+        assert_eq!(
+            api.connection.transfers,
+            vec![
+                (address, MAX_TRANSFER),
+                (address + MAX_TRANSFER as u32, MAX_TRANSFER),
+                (address + 2 * MAX_TRANSFER as u32, 1001),
+            ]
+        );
+        assert_eq!(device.memory(address, data.len()), data);
+    }
+
+    #[test]
+    fn writes_odd_lengths_in_one_transfer() {
+        let (device, mut api) = connect();
+        let address = api.get_system_info().image_buffer_base;
+
+        for length in [1, 3, 4095, MAX_TRANSFER - 1, MAX_TRANSFER] {
+            let data = vec![length as u8; length];
+            api.set_memory(address, &data).unwrap();
+            assert_eq!(device.memory(address, length), data);
+        }
+
+        let lengths: Vec<_> = api
+            .connection
+            .transfers
+            .iter()
+            .map(|(_, length)| *length)
+            .collect();
+        assert_eq!(lengths, vec![1, 3, 4095, MAX_TRANSFER - 1, MAX_TRANSFER]);
+    }
+
+    #[test]
+    fn rejects_writes_beyond_address_space() {
+        let (_, mut api) = connect();
+
+        let result = api.set_memory(u32::MAX - 9, &[0; 11]);
+        assert!(matches!(
+            result,
+            Err(Error::MemoryOverflow {
+                address: 0xffff_fff6,
+                length: 11
+            })
+        ));
+        assert!(api.connection.transfers.is_empty());
+
+        // Data ending at the last byte of the address space (0xffffffff) gets sent, the simulated
+        // controller has no memory there though
+        let result = api.set_memory(u32::MAX - 9, &[0; 10]);
+        assert!(matches!(result, Err(Error::CommandFailed { .. })));
+        assert_eq!(api.connection.transfers, vec![(0xffff_fff6, 10)]);
+
+        // Nothing to write, even at the very end
+        api.set_memory(u32::MAX, &[]).unwrap();
+        assert_eq!(api.connection.transfers.len(), 1);
+    }
+
+    #[test]
+    fn centers_one_bit_images_on_whole_bytes() {
+        let mut api = API::new(MockDevice::new(1448, 1072), 1024, 768).unwrap();
+        assert_eq!(api.centered_area(), Area::new(212, 152, 1024, 768));
+
+        api.set_memory_register_value(UP1SR_REG, UP1SR_1BPP_FLAG)
+            .unwrap();
+        let area = api.centered_area();
+        assert_eq!(area, Area::new(208, 152, 1024, 768));
+
+        let address = api.get_system_info().image_buffer_base;
+        api.display_image(address, Mode::A2).unwrap();
+    }
+
+    #[test]
+    fn rejects_unaligned_one_bit_areas() {
+        let (_, mut api) = connect();
+        api.check_area(Area::new(4, 0, 64, 8)).unwrap();
+
+        api.set_memory_register_value(UP1SR_REG, UP1SR_1BPP_FLAG)
+            .unwrap();
+        api.check_area(Area::new(8, 0, 64, 8)).unwrap();
+        assert!(matches!(
+            api.check_area(Area::new(4, 0, 64, 8)),
+            Err(Error::InvalidGeometry(_))
+        ));
+        assert!(matches!(
+            api.check_area(Area::new(8, 0, 48, 8)),
+            Err(Error::InvalidGeometry(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_transfers_longer_than_length_field() {
+        let (_, mut api) = connect();
+
+        let result = api.fast_write(0, &vec![0; u16::MAX as usize + 1]);
+        assert!(matches!(
+            result,
+            Err(Error::MemoryOverflow {
+                address: 0,
+                length: 65536
+            })
+        ));
+        assert!(api.connection.transfers.is_empty());
+    }
+}
@@ -0,0 +1,463 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use bincode::config::Options;
+use serde::{Deserialize, Serialize};
+
+use it8951::dither::{BitDepth, Frame};
+use it8951::player::VideoFrame;
+
+/// Every frame cache file starts with these bytes.
+const MAGIC: [u8; 8] = *b"IT8951FC";
+
+/// Version of the file format, increase it when the layout changes.
+const VERSION: u32 = 1;
+
+/// Byte size of every index entry: offset, presentation time in microseconds and flags of the
+/// frame.
+const INDEX_ENTRY_SIZE: u64 = 17;
+
+/// Flag of frames which start a new scene.
+const FLAG_SCENE_CUT: u8 = 1 << 0;
+
+/// Header at the beginning of every frame cache file.
+///
+/// The header is followed by the frame data, the offsets, presentation times and flags of all
+/// frames are stored in an index at the end of the file. All numbers are encoded in little endian.
+#[repr(C)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct Header {
+    /// Always "IT8951FC".
+    magic: [u8; 8],
+
+    /// Version of the file format.
+    version: u32,
+
+    /// Width of every frame.
+    pub width: u32,
+
+    /// Height of every frame.
+    pub height: u32,
+
+    /// Number of bits used to represent one pixel.
+    pub bits_per_pixel: u8,
+
+    /// Frame rate numerator of the original video.
+    pub frame_rate_num: u32,
+
+    /// Frame rate denominator of the original video.
+    pub frame_rate_den: u32,
+
+    /// Only every nth frame of the original video has been taken.
+    pub take: u32,
+
+    /// Number of frames stored in this file.
+    pub frame_count: u32,
+
+    /// Position of the frame offset index in the file.
+    index_offset: u64,
+}
+
+impl Header {
+    /// Byte size of every frame.
+    pub fn frame_size(&self) -> usize {
+        (self.width as u64 * self.height as u64 * self.bits_per_pixel as u64).div_ceil(8) as usize
+    }
+
+    /// Number of bits used to represent one pixel.
+    pub fn bit_depth(&self) -> BitDepth {
+        BitDepth::try_from(self.bits_per_pixel).expect("bit depth got validated before")
+    }
+}
+
+fn bincode_options() -> impl Options {
+    bincode::options()
+        .with_little_endian()
+        .with_fixint_encoding()
+}
+
+fn header_size() -> u64 {
+    bincode_options()
+        .serialized_size(&Header {
+            magic: MAGIC,
+            version: VERSION,
+            width: 0,
+            height: 0,
+            bits_per_pixel: 0,
+            frame_rate_num: 0,
+            frame_rate_den: 0,
+            take: 0,
+            frame_count: 0,
+            index_offset: 0,
+        })
+        .expect("header size is known")
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Position, presentation time and flags of a frame in the file.
+#[derive(Clone, Copy)]
+struct IndexEntry {
+    offset: u64,
+    timestamp: Duration,
+    flags: u8,
+}
+
+/// Write pre-processed frames into a file so they can be played later.
+pub struct FrameCacheWriter {
+    file: BufWriter<File>,
+    header: Header,
+    index: Vec<IndexEntry>,
+    position: u64,
+}
+
+impl FrameCacheWriter {
+    /// Create a new frame cache file, overwriting any existing one.
+    pub fn create(
+        path: &Path,
+        width: u32,
+        height: u32,
+        depth: BitDepth,
+        frame_rate: (u32, u32),
+        take: u32,
+    ) -> io::Result<Self> {
+        if frame_rate.0 == 0 || frame_rate.1 == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "frame rate must not be zero",
+            ));
+        }
+
+        let header = Header {
+            magic: MAGIC,
+            version: VERSION,
+            width,
+            height,
+            bits_per_pixel: depth.bits(),
+            frame_rate_num: frame_rate.0,
+            frame_rate_den: frame_rate.1,
+            take,
+            frame_count: 0,
+            index_offset: 0,
+        };
+
+        let mut file = BufWriter::new(File::create(path)?);
+
+        // Write preliminary header, it gets updated as soon as we know about all frames
+        bincode_options()
+            .serialize_into(&mut file, &header)
+            .map_err(|_| invalid_data("could not write header"))?;
+
+        Ok(Self {
+            file,
+            header,
+            index: Vec::new(),
+            position: header_size(),
+        })
+    }
+
+    /// Append frame to file.
+    pub fn write_frame(&mut self, frame: &VideoFrame) -> io::Result<()> {
+        if frame.data.len() != self.header.frame_size() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "frame size does not match dimensions",
+            ));
+        }
+
+        let mut flags = 0;
+        if frame.scene_cut {
+            flags |= FLAG_SCENE_CUT;
+        }
+
+        self.file.write_all(&frame.data)?;
+        self.index.push(IndexEntry {
+            offset: self.position,
+            timestamp: frame.timestamp,
+            flags,
+        });
+        self.position += frame.data.len() as u64;
+
+        Ok(())
+    }
+
+    /// Write index of frame offsets, presentation times and flags and finalize header.
+    pub fn finish(mut self) -> io::Result<Header> {
+        for entry in &self.index {
+            let timestamp: u64 = entry
+                .timestamp
+                .as_micros()
+                .try_into()
+                .map_err(|_| invalid_data("timestamp too large"))?;
+
+            self.file.write_all(&entry.offset.to_le_bytes())?;
+            self.file.write_all(&timestamp.to_le_bytes())?;
+            self.file.write_all(&[entry.flags])?;
+        }
+
+        self.header.frame_count = self
+            .index
+            .len()
+            .try_into()
+            .map_err(|_| invalid_data("too many frames"))?;
+        self.header.index_offset = self.position;
+
+        self.file.seek(SeekFrom::Start(0))?;
+        bincode_options()
+            .serialize_into(&mut self.file, &self.header)
+            .map_err(|_| invalid_data("could not write header"))?;
+        self.file.flush()?;
+
+        Ok(self.header)
+    }
+}
+
+/// Read pre-processed frames from a frame cache file.
+pub struct FrameCacheReader {
+    file: BufReader<File>,
+    header: Header,
+    index: Vec<IndexEntry>,
+    position: u64,
+    next_frame: usize,
+}
+
+impl FrameCacheReader {
+    /// Open frame cache file and read its header and index.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+
+        let header: Header = bincode_options()
+            .deserialize_from(&mut file)
+            .map_err(|_| invalid_data("could not read header"))?;
+
+        if header.magic != MAGIC {
+            return Err(invalid_data("not a frame cache file"));
+        }
+
+        if header.version != VERSION {
+            return Err(invalid_data("unsupported frame cache version"));
+        }
+
+        if BitDepth::try_from(header.bits_per_pixel).is_err() {
+            return Err(invalid_data("unsupported bit depth"));
+        }
+
+        if header.frame_rate_num == 0 || header.frame_rate_den == 0 {
+            return Err(invalid_data("invalid frame rate"));
+        }
+
+        // Index has to fit into the file before we allocate space for it
+        let file_length = file.get_ref().metadata()?.len();
+        let index_end = (header.frame_count as u64)
+            .checked_mul(INDEX_ENTRY_SIZE)
+            .and_then(|size| size.checked_add(header.index_offset))
+            .unwrap_or(u64::MAX);
+        if header.index_offset < header_size() || index_end > file_length {
+            return Err(invalid_data("frame index exceeds file"));
+        }
+
+        // Read index with offsets, presentation times and flags of all frames
+        file.seek(SeekFrom::Start(header.index_offset))?;
+        let mut index = Vec::with_capacity(header.frame_count as usize);
+        for _ in 0..header.frame_count {
+            let mut offset = [0; 8];
+            file.read_exact(&mut offset)?;
+
+            let mut timestamp = [0; 8];
+            file.read_exact(&mut timestamp)?;
+
+            let mut flags = [0; 1];
+            file.read_exact(&mut flags)?;
+
+            // Frames are stored between header and index
+            let offset = u64::from_le_bytes(offset);
+            let frame_end = offset.saturating_add(header.frame_size() as u64);
+            if offset < header_size() || frame_end > header.index_offset {
+                return Err(invalid_data("frame exceeds file"));
+            }
+
+            index.push(IndexEntry {
+                offset,
+                timestamp: Duration::from_micros(u64::from_le_bytes(timestamp)),
+                flags: flags[0],
+            });
+        }
+
+        let position = header_size();
+        file.seek(SeekFrom::Start(position))?;
+
+        Ok(Self {
+            file,
+            header,
+            index,
+            position,
+            next_frame: 0,
+        })
+    }
+
+    /// Returns true if the file at this path is a frame cache file.
+    pub fn is_frame_cache(path: &Path) -> io::Result<bool> {
+        let mut magic = [0; 8];
+        match File::open(path)?.read_exact(&mut magic) {
+            Ok(()) => Ok(magic == MAGIC),
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Read frame at given index.
+    pub fn read_frame(&mut self, index: usize) -> io::Result<VideoFrame> {
+        let entry = *self
+            .index
+            .get(index)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "frame out of range"))?;
+
+        // Only seek when frames are not read in order, as this discards the buffer
+        if entry.offset != self.position {
+            self.file.seek(SeekFrom::Start(entry.offset))?;
+        }
+
+        let mut frame: Frame = vec![0; self.header.frame_size()];
+        self.file.read_exact(&mut frame)?;
+        self.position = entry.offset + frame.len() as u64;
+        self.next_frame = index + 1;
+
+        Ok(VideoFrame {
+            data: frame,
+            timestamp: entry.timestamp,
+            scene_cut: entry.flags & FLAG_SCENE_CUT != 0,
+        })
+    }
+}
+
+impl Iterator for FrameCacheReader {
+    type Item = io::Result<VideoFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next_frame >= self.index.len() {
+            return None;
+        }
+
+        Some(self.read_frame(self.next_frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Path of a file in the temporary directory which is unique for every test.
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("it8951-{}-{}.it8951", std::process::id(), name))
+    }
+
+    /// Write a file with two 32x2 1bpp frames, the second one starting a new scene. It is shown
+    /// later than the frame rate suggests, like in videos with a variable frame rate.
+    fn write_file(path: &Path) -> Header {
+        let mut writer = FrameCacheWriter::create(path, 32, 2, BitDepth::One, (25, 1), 5).unwrap();
+        for (value, timestamp, scene_cut) in [(0x00, 0, false), (0xff, 312_345, true)] {
+            let frame = VideoFrame {
+                data: vec![value; 8],
+                timestamp: Duration::from_micros(timestamp),
+                scene_cut,
+            };
+            writer.write_frame(&frame).unwrap();
+        }
+
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn reads_written_frames() {
+        let path = temp_path("round-trip");
+        let header = write_file(&path);
+        let reader = FrameCacheReader::open(&path).unwrap();
+        assert_eq!(*reader.header(), header);
+
+        let frames: Vec<_> = reader.map(|frame| frame.unwrap()).collect();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1].data, vec![0xff; 8]);
+        assert!(!frames[0].scene_cut && frames[1].scene_cut);
+        assert_eq!(frames[0].timestamp, Duration::ZERO);
+        assert_eq!(frames[1].timestamp, Duration::from_micros(312_345));
+    }
+
+    #[test]
+    fn rejects_zero_frame_rate() {
+        let path = temp_path("zero-frame-rate");
+        for frame_rate in [(0, 0), (0, 1), (25, 0)] {
+            let result = FrameCacheWriter::create(&path, 32, 2, BitDepth::One, frame_rate, 5);
+            assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+        }
+
+        // Frame rate numerator follows magic, version, width, height and bits per pixel
+        write_file(&path);
+        let mut data = fs::read(&path).unwrap();
+        data[21..25].copy_from_slice(&0u32.to_le_bytes());
+        fs::write(&path, data).unwrap();
+
+        let result = FrameCacheReader::open(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let path = temp_path("other-version");
+        write_file(&path);
+        let data = fs::read(&path).unwrap();
+
+        // Version follows the magic bytes
+        for version in [0u32, VERSION + 1] {
+            let mut changed = data.clone();
+            changed[8..12].copy_from_slice(&version.to_le_bytes());
+            fs::write(&path, changed).unwrap();
+
+            let result = FrameCacheReader::open(&path);
+            assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_corrupted_index() {
+        let path = temp_path("corrupted-index");
+        write_file(&path);
+        let data = fs::read(&path).unwrap();
+
+        // Header ends with frame count (at 33) and index offset (at 37), two 8 byte frames follow it
+        let index_offset = header_size() as usize + 16;
+        let corruptions: [(usize, &[u8]); 5] = [
+            (33, &u32::MAX.to_le_bytes()),
+            (33, &3u32.to_le_bytes()),
+            (37, &u64::MAX.to_le_bytes()),
+            (37, &0u64.to_le_bytes()),
+            (index_offset + 17, &(index_offset as u64 - 7).to_le_bytes()),
+        ];
+
+        for (position, bytes) in corruptions {
+            let mut corrupted = data.clone();
+            corrupted[position..position + bytes.len()].copy_from_slice(bytes);
+            fs::write(&path, corrupted).unwrap();
+
+            let result = FrameCacheReader::open(&path);
+            assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,48 @@
+//! Schedule frames against the wall clock by their presentation time.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Wall clock the presentation times of frames are scheduled against.
+///
+/// The clock starts as soon as the first frame is displayed, all following frames are displayed
+/// relative to it.
+pub(crate) struct PlaybackClock {
+    start: Option<(Instant, Duration)>,
+}
+
+impl PlaybackClock {
+    pub(crate) fn new() -> Self {
+        Self { start: None }
+    }
+
+    /// Wall clock time at which the frame with the given presentation time should be displayed.
+    fn deadline(&self, timestamp: Duration) -> Option<Instant> {
+        self.start
+            .map(|(instant, first)| instant + timestamp.saturating_sub(first))
+    }
+
+    /// Returns true if the frame with the given presentation time should already be displayed.
+    pub(crate) fn is_due(&self, timestamp: Duration) -> bool {
+        match self.deadline(timestamp) {
+            Some(deadline) => Instant::now() >= deadline,
+            None => false,
+        }
+    }
+
+    /// Block until the frame with the given presentation time should be displayed. Starts the
+    /// clock when no frame has been displayed yet.
+    pub(crate) fn wait_until(&mut self, timestamp: Duration) {
+        match self.deadline(timestamp) {
+            Some(deadline) => {
+                let now = Instant::now();
+                if deadline > now {
+                    thread::sleep(deadline - now);
+                }
+            }
+            None => {
+                self.start = Some((Instant::now(), timestamp));
+            }
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! Find the areas which changed between two frames, so only those need to be uploaded and
+//! refreshed.
+
+use crate::api::{Area, ONE_BIT_WIDTH_ALIGNMENT};
+use crate::dither::BitDepth;
+
+/// Rows without changes which may be part of an area, instead of splitting it in two.
+const MAX_ROW_GAP: u32 = 16;
+
+/// Maximum number of areas, more get merged as every display command takes its time.
+const MAX_AREAS: usize = 8;
+
+/// Find the areas in which two packed frames of the same size differ.
+///
+/// Every area spans over horizontal bands of changed rows, its position and width are aligned
+/// to 32 pixels so it can be displayed in 1bpp mode as well. Returns `None` when the areas cover
+/// more than the given share (0.0 to 1.0) of the frame, a full refresh is the better choice then.
+/// The same goes for frames whose rows do not start with a new byte. An empty list is returned for
+/// identical frames.
+pub fn changed_areas(
+    previous: &[u8],
+    current: &[u8],
+    width: u32,
+    height: u32,
+    depth: BitDepth,
+    max_coverage: f32,
+) -> Option<Vec<Area>> {
+    if !depth.rows_are_aligned(width) {
+        return None;
+    }
+
+    let row_bytes = depth.frame_size(width, 1);
+    let pixels_per_byte = 8 / depth.bits() as u32;
+    let mut areas: Vec<Area> = Vec::new();
+
+    for y in 0..height {
+        let row = y as usize * row_bytes..(y as usize + 1) * row_bytes;
+        let (previous_row, current_row) = (&previous[row.clone()], &current[row]);
+
+        let first = match previous_row
+            .iter()
+            .zip(current_row)
+            .position(|(a, b)| a != b)
+        {
+            Some(first) => first as u32,
+            None => continue,
+        };
+        let last = previous_row
+            .iter()
+            .zip(current_row)
+            .rposition(|(a, b)| a != b)
+            .expect("row contains a change") as u32;
+
+        // Convert changed bytes into aligned pixel positions
+        let left = first * pixels_per_byte / ONE_BIT_WIDTH_ALIGNMENT * ONE_BIT_WIDTH_ALIGNMENT;
+        let right = ((last + 1) * pixels_per_byte)
+            .next_multiple_of(ONE_BIT_WIDTH_ALIGNMENT)
+            .min(width);
+
+        // Extend the current band when it ended only a few rows ago
+        match areas.last_mut() {
+            Some(area) if y - (area.y + area.height) <= MAX_ROW_GAP => {
+                let area_right = (area.x + area.width).max(right);
+                area.x = area.x.min(left);
+                area.width = area_right - area.x;
+                area.height = y + 1 - area.y;
+            }
+            _ => areas.push(Area::new(left, y, right - left, 1)),
+        }
+    }
+
+    // Merge bands which are closest to each other until there are few enough
+    while areas.len() > MAX_AREAS {
+        let index = (0..areas.len() - 1)
+            .min_by_key(|&index| areas[index + 1].y - (areas[index].y + areas[index].height))
+            .expect("there are multiple areas");
+        let next = areas.remove(index + 1);
+        let area = &mut areas[index];
+
+        let right = (area.x + area.width).max(next.x + next.width);
+        area.x = area.x.min(next.x);
+        area.width = right - area.x;
+        area.height = next.y + next.height - area.y;
+    }
+
+    let covered: u64 = areas
+        .iter()
+        .map(|area| area.width as u64 * area.height as u64)
+        .sum();
+    if covered as f64 > (width as u64 * height as u64) as f64 * max_coverage as f64 {
+        return None;
+    }
+
+    Some(areas)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: u32 = 256;
+    const HEIGHT: u32 = 256;
+
+    /// Two frames of 1bpp which differ in one byte of each of the given rows.
+    fn frames_changed_in(rows: &[u32], byte: usize) -> (Vec<u8>, Vec<u8>) {
+        let row_bytes = BitDepth::One.frame_size(WIDTH, 1);
+        let previous = vec![0; BitDepth::One.frame_size(WIDTH, HEIGHT)];
+        let mut current = previous.clone();
+        for &y in rows {
+            current[y as usize * row_bytes + byte] = 0x01;
+        }
+
+        (previous, current)
+    }
+
+    #[test]
+    fn finds_nothing_in_identical_frames() {
+        let (frame, _) = frames_changed_in(&[], 0);
+        assert_eq!(
+            changed_areas(&frame, &frame, WIDTH, HEIGHT, BitDepth::One, 0.5),
+            Some(vec![])
+        );
+    }
+
+    #[test]
+    fn aligns_changed_bytes_to_32_pixels() {
+        // Byte 5 holds pixels 40 to 47
+        let (previous, current) = frames_changed_in(&[10], 5);
+        assert_eq!(
+            changed_areas(&previous, &current, WIDTH, HEIGHT, BitDepth::One, 0.5),
+            Some(vec![Area::new(32, 10, 32, 1)])
+        );
+
+        // With 4bpp byte 20 holds pixels 40 and 41
+        let depth = BitDepth::Four;
+        let previous = vec![0; depth.frame_size(WIDTH, HEIGHT)];
+        let mut current = previous.clone();
+        current[3 * depth.frame_size(WIDTH, 1) + 20] = 0xf0;
+        assert_eq!(
+            changed_areas(&previous, &current, WIDTH, HEIGHT, depth, 0.5),
+            Some(vec![Area::new(32, 3, 32, 1)])
+        );
+    }
+
+    #[test]
+    fn merges_rows_with_small_gaps() {
+        // 16 unchanged rows in between still belong to the same area
+        let (previous, current) = frames_changed_in(&[10, 27], 0);
+        assert_eq!(
+            changed_areas(&previous, &current, WIDTH, HEIGHT, BitDepth::One, 0.5),
+            Some(vec![Area::new(0, 10, 32, 18)])
+        );
+
+        // .. 17 start a new one
+        let (previous, current) = frames_changed_in(&[10, 28], 0);
+        assert_eq!(
+            changed_areas(&previous, &current, WIDTH, HEIGHT, BitDepth::One, 0.5),
+            Some(vec![Area::new(0, 10, 32, 1), Area::new(0, 28, 32, 1)])
+        );
+    }
+
+    #[test]
+    fn merges_closest_areas_down_to_maximum() {
+        // 11 bands, the first ones are closest to each other
+        let rows = [0, 18, 36, 54, 72, 100, 130, 160, 190, 220, 250];
+        let (previous, current) = frames_changed_in(&rows, 0);
+        let areas = changed_areas(&previous, &current, WIDTH, HEIGHT, BitDepth::One, 0.5).unwrap();
+
+        assert_eq!(areas.len(), MAX_AREAS);
+        assert_eq!(areas[0], Area::new(0, 0, 32, 55));
+        assert!(rows.iter().all(|&y| areas
+            .iter()
+            .any(|area| (area.y..area.y + area.height).contains(&y))));
+    }
+
+    #[test]
+    fn refreshes_large_changes_completely() {
+        let rows: Vec<u32> = (0..HEIGHT).collect();
+        let (previous, current) = frames_changed_in(&rows, 0);
+
+        // A column of 32 pixels covers an eighth of the frame
+        assert_eq!(
+            changed_areas(&previous, &current, WIDTH, HEIGHT, BitDepth::One, 0.1),
+            None
+        );
+        assert_eq!(
+            changed_areas(&previous, &current, WIDTH, HEIGHT, BitDepth::One, 0.125),
+            Some(vec![Area::new(0, 0, 32, HEIGHT)])
+        );
+    }
+
+    #[test]
+    fn refreshes_unaligned_rows_completely() {
+        let depth = BitDepth::Four;
+        let size = depth.frame_size(101, 3);
+        let (previous, mut current) = (vec![0; size], vec![0; size]);
+        current[size - 1] = 0x0f;
+
+        assert_eq!(changed_areas(&previous, &current, 101, 3, depth, 1.0), None);
+    }
+}
@@ -0,0 +1,1000 @@
+//! Dither 8bpp grayscale images and pack them into frames of 1, 2 or 4 bits per pixel.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+
+use rayon::prelude::*;
+
+/// Frames with fewer pixels are packed on the calling thread, handing them over to the thread pool
+/// would take longer than packing them.
+const PARALLEL_MIN_PIXELS: u32 = 64 * 1024;
+
+/// Single video frame to be displayed on e-paper. It contains multiple bytes where every bit of it
+/// represents a pixel (1 = white, 0 = black).
+///
+/// Frames with more gray levels use 2 or 4 bits per pixel, 0 is black and the highest value
+/// white. Pixels are always packed starting with the lowest bits of every byte. See
+/// [`BitmapFormat`] for controllers expecting 1bpp images in another layout.
+pub type Frame = Vec<u8>;
+
+/// Number of bits used to represent one pixel in a frame.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BitDepth {
+    /// Black and white only.
+    One,
+
+    /// 4 gray levels.
+    Two,
+
+    /// 16 gray levels.
+    Four,
+}
+
+impl BitDepth {
+    /// Number of bits used to represent one pixel.
+    pub fn bits(&self) -> u8 {
+        match self {
+            BitDepth::One => 1,
+            BitDepth::Two => 2,
+            BitDepth::Four => 4,
+        }
+    }
+
+    /// Number of gray levels which can be represented.
+    pub fn levels(&self) -> u32 {
+        1 << self.bits()
+    }
+
+    /// Byte size of a frame with the given dimensions. Pixels are packed continuously, when they
+    /// do not fill the last byte completely it is padded.
+    pub fn frame_size(&self, width: u32, height: u32) -> usize {
+        (width as usize * height as usize * self.bits() as usize).div_ceil(8)
+    }
+
+    /// Returns true if every row of a frame with the given width starts with a new byte.
+    pub fn rows_are_aligned(&self, width: u32) -> bool {
+        width * self.bits() as u32 % 8 == 0
+    }
+
+    /// Convert packed frame into 8bpp grayscale image, as this is the only format besides 1bpp
+    /// which the display engine of the controller can read from memory. Pixels padding the last
+    /// byte are included.
+    pub fn unpack(&self, frame: &[u8]) -> Vec<u8> {
+        let bits = self.bits() as usize;
+        let mask = (self.levels() - 1) as u8;
+        let gray_step = 255 / mask;
+
+        (0..frame.len() * 8 / bits)
+            .map(|index| {
+                let level = (frame[index * bits / 8] >> ((index * bits) % 8)) & mask;
+                level * gray_step
+            })
+            .collect()
+    }
+}
+
+impl TryFrom<u8> for BitDepth {
+    type Error = String;
+
+    fn try_from(bits: u8) -> Result<Self, Self::Error> {
+        match bits {
+            1 => Ok(BitDepth::One),
+            2 => Ok(BitDepth::Two),
+            4 => Ok(BitDepth::Four),
+            _ => Err(format!("unsupported bit depth {}", bits)),
+        }
+    }
+}
+
+impl FromStr for BitDepth {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let bits: u8 = value
+            .parse()
+            .map_err(|_| format!("invalid bit depth '{}'", value))?;
+        BitDepth::try_from(bits)
+    }
+}
+
+/// Order of the pixels within every byte of a 1bpp image in the controller memory.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BitOrder {
+    /// First pixel in the lowest bit, as read by most controllers.
+    LsbFirst,
+
+    /// First pixel in the highest bit.
+    MsbFirst,
+}
+
+impl FromStr for BitOrder {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "lsb" => Ok(BitOrder::LsbFirst),
+            "msb" => Ok(BitOrder::MsbFirst),
+            _ => Err(format!("invalid bit order '{}', use lsb or msb", value)),
+        }
+    }
+}
+
+/// How 1bpp images are stored in the controller memory and which gray values the controller
+/// displays their pixels with. Controller firmwares and panels differ in the bit order and
+/// polarity they expect.
+///
+/// Frames are always dithered in the layout described at [`Frame`] and only converted into this
+/// format when they get uploaded, so prepared frames can be shown on any panel.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BitmapFormat {
+    /// Order of the pixels in every byte.
+    pub bit_order: BitOrder,
+
+    /// Set bits represent black pixels instead of white ones.
+    pub invert: bool,
+
+    /// Gray value of black pixels, the upper 4 bits are used by the controller.
+    pub foreground: u8,
+
+    /// Gray value of white pixels, the upper 4 bits are used by the controller.
+    pub background: u8,
+}
+
+impl BitmapFormat {
+    /// Convert 1bpp frame into the layout of this format.
+    pub fn encode<'a>(&self, frame: &'a [u8]) -> Cow<'a, [u8]> {
+        match (self.bit_order, self.invert) {
+            (BitOrder::LsbFirst, false) => Cow::Borrowed(frame),
+            (BitOrder::LsbFirst, true) => Cow::Owned(frame.iter().map(|byte| !byte).collect()),
+            (BitOrder::MsbFirst, invert) => Cow::Owned(
+                frame
+                    .iter()
+                    .map(|byte| if invert { !byte } else { *byte }.reverse_bits())
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Value of the bitmap color definition register ([`crate::api::BGVR_REG`]): The gray value of
+    /// set bits in the lower byte and the one of cleared bits in the byte above.
+    pub fn colors(&self) -> u32 {
+        let (set, cleared) = if self.invert {
+            (self.foreground, self.background)
+        } else {
+            (self.background, self.foreground)
+        };
+
+        set as u32 | (cleared as u32) << 8
+    }
+}
+
+impl Default for BitmapFormat {
+    /// Layout of [`Frame`]s, displayed in black (0x00) and white (0xf0).
+    fn default() -> Self {
+        BitmapFormat {
+            bit_order: BitOrder::LsbFirst,
+            invert: false,
+            foreground: 0x00,
+            background: 0xf0,
+        }
+    }
+}
+
+/// Convert a grayscale image into an image which only contains black or white pixels (or a few
+/// gray levels, depending on the bit depth).
+pub trait Dither: Send {
+    /// Dither grayscale image and convert it to raw format, representing black (0) or white (1)
+    /// pixels in an array.
+    ///
+    /// The stride is the number of bytes of every row in the grayscale image, which can be larger
+    /// than the width.
+    fn dither(&self, data_8bpp: &[u8], width: u32, height: u32, stride: usize) -> Frame;
+}
+
+/// Available dithering algorithms.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DitherMethod {
+    /// Ordered dithering with a Bayer matrix.
+    Bayer,
+
+    /// Ordered dithering with a blue-noise threshold map.
+    BlueNoise,
+
+    /// Floyd–Steinberg error diffusion.
+    FloydSteinberg,
+
+    /// Atkinson error diffusion, only diffusing parts of the error which results in more contrast.
+    Atkinson,
+
+    /// Sierra Lite error diffusion.
+    SierraLite,
+
+    /// Plain thresholding without any dithering.
+    Threshold,
+}
+
+impl DitherMethod {
+    /// Names of all dithering algorithms as accepted by `from_str`.
+    pub const VARIANTS: [&'static str; 6] = [
+        "bayer",
+        "blue-noise",
+        "floyd-steinberg",
+        "atkinson",
+        "sierra-lite",
+        "threshold",
+    ];
+
+    /// Create dithering algorithm quantizing to the gray levels of the given bit depth.
+    pub fn create(&self, depth: BitDepth) -> Box<dyn Dither> {
+        match self {
+            DitherMethod::Bayer => Box::new(ThresholdMatrix::bayer(depth)),
+            DitherMethod::BlueNoise => Box::new(ThresholdMatrix::blue_noise(depth)),
+            DitherMethod::FloydSteinberg => Box::new(ErrorDiffusion::floyd_steinberg(depth)),
+            DitherMethod::Atkinson => Box::new(ErrorDiffusion::atkinson(depth)),
+            DitherMethod::SierraLite => Box::new(ErrorDiffusion::sierra_lite(depth)),
+            DitherMethod::Threshold => Box::new(Threshold::new(depth)),
+        }
+    }
+}
+
+impl FromStr for DitherMethod {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "bayer" => Ok(DitherMethod::Bayer),
+            "blue-noise" => Ok(DitherMethod::BlueNoise),
+            "floyd-steinberg" => Ok(DitherMethod::FloydSteinberg),
+            "atkinson" => Ok(DitherMethod::Atkinson),
+            "sierra-lite" => Ok(DitherMethod::SierraLite),
+            "threshold" => Ok(DitherMethod::Threshold),
+            _ => Err(format!("unknown dithering algorithm '{}'", value)),
+        }
+    }
+}
+
+impl fmt::Display for DitherMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            DitherMethod::Bayer => "bayer",
+            DitherMethod::BlueNoise => "blue-noise",
+            DitherMethod::FloydSteinberg => "floyd-steinberg",
+            DitherMethod::Atkinson => "atkinson",
+            DitherMethod::SierraLite => "sierra-lite",
+            DitherMethod::Threshold => "threshold",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// Keep dithered pixels stable between the frames of a video, so static areas do not flicker.
+///
+/// Dithering flips many pixels between frames even when the picture barely changed, which causes
+/// needless transitions and shimmer on the panel. Every pixel keeps its level of the previous
+/// frame here, unless its gray value moved further than the hysteresis away from the gray value it
+/// had when its level changed the last time.
+pub struct TemporalDither {
+    dither: Box<dyn Dither>,
+    depth: BitDepth,
+    hysteresis: u8,
+
+    /// Previous frame together with the gray values its pixels are based on.
+    previous: Option<(Frame, Vec<u8>)>,
+}
+
+impl TemporalDither {
+    /// Keep pixels dithered by the given algorithm stable. A hysteresis of 0 disables this, every
+    /// frame is dithered on its own then.
+    pub fn new(dither: Box<dyn Dither>, depth: BitDepth, hysteresis: u8) -> Self {
+        Self {
+            dither,
+            depth,
+            hysteresis,
+            previous: None,
+        }
+    }
+
+    /// Bit depth of the frames.
+    pub fn depth(&self) -> BitDepth {
+        self.depth
+    }
+
+    /// Dither grayscale image, keeping the pixels of the previous frame which did not change
+    /// enough. See [`Dither::dither`] for the format.
+    pub fn dither(&mut self, data_8bpp: &[u8], width: u32, height: u32, stride: usize) -> Frame {
+        let mut frame = self.dither.dither(data_8bpp, width, height, stride);
+        if self.hysteresis == 0 {
+            return frame;
+        }
+
+        let (previous, mut references) = match self.previous.take() {
+            Some((previous, references)) if previous.len() == frame.len() => (previous, references),
+            _ => {
+                // Nothing to keep, remember gray values of all pixels
+                let references = (0..height as usize)
+                    .flat_map(|y| &data_8bpp[y * stride..y * stride + width as usize])
+                    .copied()
+                    .collect();
+                self.previous = Some((frame.clone(), references));
+                return frame;
+            }
+        };
+
+        let bits = self.depth.bits() as usize;
+        let pixel_mask = (self.depth.levels() - 1) as u8;
+        let width = width as usize;
+        let pixels = references.len();
+
+        for (byte_index, (byte, previous_byte)) in frame.iter_mut().zip(&previous).enumerate() {
+            // Collect bits of all pixels in this byte which keep their previous level, the last
+            // byte might be padded
+            let mut keep = 0u8;
+            for pixel in 0..8 / bits {
+                let index = byte_index * 8 / bits + pixel;
+                if index >= pixels {
+                    break;
+                }
+
+                let value = data_8bpp[(index / width) * stride + index % width];
+
+                if value.abs_diff(references[index]) <= self.hysteresis {
+                    keep |= pixel_mask << (pixel * bits);
+                } else {
+                    references[index] = value;
+                }
+            }
+
+            *byte = (*byte & !keep) | (previous_byte & keep);
+        }
+
+        self.previous = Some((frame.clone(), references));
+        frame
+    }
+
+    /// Forget the previous frame, for example when a new scene starts.
+    pub fn reset(&mut self) {
+        self.previous = None;
+    }
+}
+
+/// Set gray level of pixel at the given index.
+fn set_level(frame: &mut Frame, index: usize, level: u8, depth: BitDepth) {
+    let bit_index = index * depth.bits() as usize;
+    frame[bit_index / 8] |= level << (bit_index % 8);
+}
+
+/// Quantize gray value to the nearest level.
+fn nearest_level(value: i32, depth: BitDepth) -> u8 {
+    let max_level = depth.levels() as i32 - 1;
+    ((value.clamp(0, 255) * max_level + 127) / 255) as u8
+}
+
+/// Gray value of the given level.
+fn level_value(level: u8, depth: BitDepth) -> i32 {
+    level as i32 * 255 / (depth.levels() as i32 - 1)
+}
+
+/// Helper to apply ordered dithering to a field of pixels.
+pub struct ThresholdMatrix {
+    nx: u32,
+    ny: u32,
+    matrix: Vec<u8>,
+    depth: BitDepth,
+}
+
+impl ThresholdMatrix {
+    /// Bayer matrix of 256x256 pixels.
+    pub fn bayer(depth: BitDepth) -> Self {
+        let power_of_two = 8;
+        let side = 2_u32.pow(power_of_two);
+        let num_elements = side * side;
+        let norm_factor = 255_f32 / (num_elements as f32);
+
+        let mut matrix: Vec<u8> = Vec::new();
+        for x in 0..side {
+            for y in 0..side {
+                let xc = x ^ y;
+                let yc = y;
+                let mut v = 0;
+
+                for p in (0..power_of_two).rev() {
+                    let bit_idx = 2 * (power_of_two - p - 1);
+                    v |= ((yc >> p) & 1) << bit_idx;
+                    v |= ((xc >> p) & 1) << (bit_idx + 1);
+                }
+
+                matrix.push((v as f32 * norm_factor) as u8);
+            }
+        }
+
+        Self {
+            nx: side,
+            ny: side,
+            matrix,
+            depth,
+        }
+    }
+
+    /// Blue-noise threshold map of 64x64 pixels, generated with the void-and-cluster algorithm.
+    pub fn blue_noise(depth: BitDepth) -> Self {
+        let side = 64;
+        let ranks = void_and_cluster(side);
+        let norm_factor = 255_f32 / ((side * side) as f32);
+
+        Self {
+            nx: side as u32,
+            ny: side as u32,
+            matrix: ranks
+                .iter()
+                .map(|rank| (*rank as f32 * norm_factor) as u8)
+                .collect(),
+            depth,
+        }
+    }
+
+    fn look_up(&self, x: u32, y: u32) -> u8 {
+        let j = x % self.nx;
+        let i = y % self.ny;
+        let idx: usize = (i * self.nx + j)
+            .try_into()
+            .expect("i * side_length + j does not fit into usize");
+
+        self.matrix[idx]
+    }
+}
+
+impl Dither for ThresholdMatrix {
+    fn dither(&self, data_8bpp: &[u8], width: u32, height: u32, stride: usize) -> Frame {
+        let max_level = self.depth.levels() - 1;
+
+        // Split value into the level below and the remaining distance to the next one. Round up
+        // to the next level if dithering says so (for 1bpp: white pixel)
+        let level = |value: u8, threshold: u8| {
+            let scaled = value as u32 * max_level;
+            (scaled / 255 + u32::from(scaled % 255 > threshold as u32)) as u8
+        };
+
+        if !self.depth.rows_are_aligned(width) {
+            return pack_pixels(
+                data_8bpp,
+                width,
+                height,
+                stride,
+                self.depth,
+                |x, y, value| level(value, self.look_up(x, y)),
+            );
+        }
+
+        let row_length = self.nx as usize;
+        pack_rows(
+            data_8bpp,
+            width,
+            height,
+            stride,
+            self.depth,
+            |y, pixels, bytes| {
+                let start = (y % self.ny) as usize * row_length;
+                let thresholds = &self.matrix[start..start + row_length];
+
+                // Matrix rows cover whole bytes, so every part of the row can be packed with them
+                let bytes_per_part = row_length * self.depth.bits() as usize / 8;
+                for (pixels, bytes) in pixels
+                    .chunks(row_length)
+                    .zip(bytes.chunks_mut(bytes_per_part))
+                {
+                    pack_levels(pixels, bytes, self.depth, |index, value| {
+                        level(value, thresholds[index])
+                    });
+                }
+            },
+        )
+    }
+}
+
+/// Pack gray levels of all pixels into a frame, one pixel at a time. This works for all widths,
+/// even when rows do not start with a new byte.
+fn pack_pixels(
+    data_8bpp: &[u8],
+    width: u32,
+    height: u32,
+    stride: usize,
+    depth: BitDepth,
+    level: impl Fn(u32, u32, u8) -> u8,
+) -> Frame {
+    let mut frame: Frame = vec![0b0000_0000; depth.frame_size(width, height)];
+    for y in 0..height {
+        for x in 0..width {
+            let level = level(x, y, data_8bpp[y as usize * stride + x as usize]);
+            if level > 0 {
+                set_level(&mut frame, (y * width + x) as usize, level, depth);
+            }
+        }
+    }
+
+    frame
+}
+
+/// Pack rows of the grayscale image into a frame, spread across the threads of the global thread
+/// pool. Every row gets packed by the given function, which receives the row index, its pixels and
+/// its bytes in the frame. Rows need to be aligned to bytes.
+fn pack_rows(
+    data_8bpp: &[u8],
+    width: u32,
+    height: u32,
+    stride: usize,
+    depth: BitDepth,
+    pack_row: impl Fn(u32, &[u8], &mut [u8]) + Sync,
+) -> Frame {
+    let mut frame: Frame = vec![0b0000_0000; depth.frame_size(width, height)];
+    let row_bytes = depth.frame_size(width, 1);
+    if row_bytes == 0 {
+        return frame;
+    }
+
+    let pack_rows = |first_row: u32, bytes: &mut [u8]| {
+        for (index, row) in bytes.chunks_mut(row_bytes).enumerate() {
+            let y = first_row + index as u32;
+            let start = y as usize * stride;
+            pack_row(y, &data_8bpp[start..start + width as usize], row);
+        }
+    };
+
+    let threads = rayon::current_num_threads();
+    if width * height < PARALLEL_MIN_PIXELS || threads == 1 {
+        pack_rows(0, &mut frame);
+        return frame;
+    }
+
+    // A few chunks per thread, so threads which are done early can take over work of busy ones
+    let rows_per_chunk = (height as usize).div_ceil(threads * 4).max(1);
+    frame
+        .par_chunks_mut(rows_per_chunk * row_bytes)
+        .enumerate()
+        .for_each(|(index, bytes)| pack_rows((index * rows_per_chunk) as u32, bytes));
+
+    frame
+}
+
+/// Pack pixels into bytes, a whole byte at a time. The level of every pixel is calculated from its
+/// index and gray value.
+fn pack_levels(pixels: &[u8], bytes: &mut [u8], depth: BitDepth, level: impl Fn(usize, u8) -> u8) {
+    let bits = depth.bits() as usize;
+    let pixels_per_byte = 8 / bits;
+
+    for (byte_index, (byte, pixels)) in bytes
+        .iter_mut()
+        .zip(pixels.chunks(pixels_per_byte))
+        .enumerate()
+    {
+        let first = byte_index * pixels_per_byte;
+        *byte = pixels.iter().enumerate().fold(0, |byte, (index, &value)| {
+            byte | level(first + index, value) << (index * bits)
+        });
+    }
+}
+
+/// Generate a blue-noise threshold map with the void-and-cluster algorithm by Robert Ulichney.
+///
+/// Returns the rank of every pixel in a square of the given side length.
+fn void_and_cluster(side: usize) -> Vec<usize> {
+    let num_elements = side * side;
+
+    // Gaussian filter with wrap-around edges, spreading the "energy" of every pixel
+    let sigma: f32 = 1.5;
+    let kernel: Vec<f32> = (0..num_elements)
+        .map(|index| {
+            let dx = (index % side).min(side - index % side) as f32;
+            let dy = (index / side).min(side - index / side) as f32;
+            (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp()
+        })
+        .collect();
+
+    let mut pattern = vec![false; num_elements];
+    let mut energy = vec![0_f32; num_elements];
+
+    let toggle = |pattern: &mut Vec<bool>, energy: &mut Vec<f32>, index: usize| {
+        pattern[index] = !pattern[index];
+        let sign = if pattern[index] { 1.0 } else { -1.0 };
+        let (x, y) = (index % side, index / side);
+        for (other, value) in energy.iter_mut().enumerate() {
+            let dx = (other % side + side - x) % side;
+            let dy = (other / side + side - y) % side;
+            *value += sign * kernel[dy * side + dx];
+        }
+    };
+
+    // Pixel set to `state` with the highest (tightest cluster) or lowest (largest void) energy
+    let find = |pattern: &[bool], energy: &[f32], state: bool, highest: bool| -> usize {
+        let candidates = (0..num_elements).filter(|index| pattern[*index] == state);
+        if highest {
+            candidates
+                .max_by(|a, b| energy[*a].total_cmp(&energy[*b]))
+                .expect("pattern contains pixel")
+        } else {
+            candidates
+                .min_by(|a, b| energy[*a].total_cmp(&energy[*b]))
+                .expect("pattern contains pixel")
+        }
+    };
+
+    // Initial pattern: Set pixels spread deterministically over the whole area
+    let initial_ones = num_elements / 10;
+    let mut seed: u32 = 0x2545_f491;
+    let mut ones = 0;
+    while ones < initial_ones {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        let index = seed as usize % num_elements;
+        if !pattern[index] {
+            toggle(&mut pattern, &mut energy, index);
+            ones += 1;
+        }
+    }
+
+    // Move pixels from tightest clusters into largest voids until the pattern is stable
+    loop {
+        let cluster = find(&pattern, &energy, true, true);
+        toggle(&mut pattern, &mut energy, cluster);
+        let void = find(&pattern, &energy, false, false);
+
+        if void == cluster {
+            toggle(&mut pattern, &mut energy, cluster);
+            break;
+        }
+
+        toggle(&mut pattern, &mut energy, void);
+    }
+
+    let mut ranks = vec![0; num_elements];
+
+    // Phase 1: Rank pixels of the initial pattern by removing the tightest clusters first
+    let (mut phase_pattern, mut phase_energy) = (pattern.clone(), energy.clone());
+    for rank in (0..initial_ones).rev() {
+        let cluster = find(&phase_pattern, &phase_energy, true, true);
+        toggle(&mut phase_pattern, &mut phase_energy, cluster);
+        ranks[cluster] = rank;
+    }
+
+    // Phase 2 & 3: Rank all other pixels by filling the largest voids first
+    for rank in initial_ones..num_elements {
+        let void = find(&pattern, &energy, false, false);
+        toggle(&mut pattern, &mut energy, void);
+        ranks[void] = rank;
+    }
+
+    ranks
+}
+
+/// Error diffusion dithering, spreading the quantization error of every pixel to its neighbours.
+pub struct ErrorDiffusion {
+    /// Position relative to the current pixel (x, y) and weight of the error which gets added
+    /// there.
+    kernel: &'static [(i32, i32, i32)],
+
+    /// Sum of all weights the error gets divided by.
+    divisor: i32,
+
+    depth: BitDepth,
+}
+
+impl ErrorDiffusion {
+    /// Floyd-Steinberg dithering, spreading the error to four neighbours.
+    pub fn floyd_steinberg(depth: BitDepth) -> Self {
+        Self {
+            kernel: &[(1, 0, 7), (-1, 1, 3), (0, 1, 5), (1, 1, 1)],
+            divisor: 16,
+            depth,
+        }
+    }
+
+    /// Atkinson dithering, only spreading 3/4 of the error which gives more contrast.
+    pub fn atkinson(depth: BitDepth) -> Self {
+        Self {
+            kernel: &[
+                (1, 0, 1),
+                (2, 0, 1),
+                (-1, 1, 1),
+                (0, 1, 1),
+                (1, 1, 1),
+                (0, 2, 1),
+            ],
+            divisor: 8,
+            depth,
+        }
+    }
+
+    /// Sierra Lite dithering, a faster approximation of Floyd-Steinberg.
+    pub fn sierra_lite(depth: BitDepth) -> Self {
+        Self {
+            kernel: &[(1, 0, 2), (-1, 1, 1), (0, 1, 1)],
+            divisor: 4,
+            depth,
+        }
+    }
+}
+
+impl Dither for ErrorDiffusion {
+    fn dither(&self, data_8bpp: &[u8], width: u32, height: u32, stride: usize) -> Frame {
+        let width = width as usize;
+        let rows = self.kernel.iter().map(|(_, dy, _)| *dy).max().unwrap_or(0) as usize + 1;
+
+        // Accumulated errors for the current and following rows, wrapping around
+        let mut errors = vec![0_i32; rows * width];
+
+        // All divisors are powers of two, shifting is a lot faster than dividing
+        debug_assert!(self.divisor.count_ones() == 1);
+        let shift = self.divisor.trailing_zeros();
+        let round = self.divisor - 1;
+
+        // Levels are collected row by row, so whole bytes can be packed at once
+        let aligned = self.depth.rows_are_aligned(width as u32);
+        let row_bytes = self.depth.frame_size(width as u32, 1);
+        let mut levels = vec![0_u8; width];
+
+        let mut frame: Frame = vec![0b0000_0000; self.depth.frame_size(width as u32, height)];
+        for y in 0..height as usize {
+            let offsets: Vec<usize> = (0..rows).map(|dy| (y + dy) % rows * width).collect();
+
+            for x in 0..width {
+                let value = data_8bpp[y * stride + x] as i32 + errors[offsets[0] + x];
+
+                let level = nearest_level(value, self.depth);
+                levels[x] = level;
+
+                let error = value - level_value(level, self.depth);
+                if error == 0 {
+                    continue;
+                }
+
+                for &(dx, dy, weight) in self.kernel {
+                    let target_x = x as i32 + dx;
+                    if target_x >= 0 && (target_x as usize) < width {
+                        // Divide by shifting, rounding towards zero like a division would
+                        let share = error * weight;
+                        errors[offsets[dy as usize] + target_x as usize] +=
+                            (share + (share >> 31 & round)) >> shift;
+                    }
+                }
+            }
+
+            if aligned {
+                let bytes = &mut frame[y * row_bytes..(y + 1) * row_bytes];
+                pack_levels(&levels, bytes, self.depth, |_, level| level);
+            } else {
+                for (x, &level) in levels.iter().enumerate() {
+                    if level > 0 {
+                        set_level(&mut frame, y * width + x, level, self.depth);
+                    }
+                }
+            }
+
+            // Row is done, it will be reused for the errors of a following one
+            errors[offsets[0]..offsets[0] + width].fill(0);
+        }
+
+        frame
+    }
+}
+
+/// Plain thresholding, every pixel gets the nearest gray level (for 1bpp: every pixel brighter
+/// than half of the range is white).
+pub struct Threshold {
+    depth: BitDepth,
+}
+
+impl Threshold {
+    /// Threshold pixels to the gray levels of the given bit depth.
+    pub fn new(depth: BitDepth) -> Self {
+        Self { depth }
+    }
+}
+
+impl Dither for Threshold {
+    fn dither(&self, data_8bpp: &[u8], width: u32, height: u32, stride: usize) -> Frame {
+        let level = |value: u8| nearest_level(value as i32, self.depth);
+
+        if !self.depth.rows_are_aligned(width) {
+            return pack_pixels(
+                data_8bpp,
+                width,
+                height,
+                stride,
+                self.depth,
+                |_, _, value| level(value),
+            );
+        }
+
+        pack_rows(
+            data_8bpp,
+            width,
+            height,
+            stride,
+            self.depth,
+            |_, pixels, bytes| pack_levels(pixels, bytes, self.depth, |_, value| level(value)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEPTHS: [BitDepth; 3] = [BitDepth::One, BitDepth::Two, BitDepth::Four];
+
+    /// Noisy gradient with padding after every row, large enough to be packed in parallel.
+    fn gray_image(width: u32, height: u32, stride: usize) -> Vec<u8> {
+        let mut seed = 1u32;
+        (0..height as usize * stride)
+            .map(|index| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                let gradient = (index % stride) as u32 * 255 / width;
+                (gradient + (seed >> 16) % 64).min(255) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn packs_rows_like_single_pixels() {
+        let (width, height, stride) = (512, 160, 520);
+        assert!(width * height >= PARALLEL_MIN_PIXELS);
+        let image = gray_image(width, height, stride);
+
+        for depth in DEPTHS {
+            for matrix in [
+                ThresholdMatrix::bayer(depth),
+                ThresholdMatrix::blue_noise(depth),
+            ] {
+                let max_level = depth.levels() - 1;
+                let expected = pack_pixels(&image, width, height, stride, depth, |x, y, value| {
+                    let scaled = value as u32 * max_level;
+                    let threshold = matrix.look_up(x, y) as u32;
+                    (scaled / 255 + u32::from(scaled % 255 > threshold)) as u8
+                });
+                assert_eq!(matrix.dither(&image, width, height, stride), expected);
+            }
+
+            let expected = pack_pixels(&image, width, height, stride, depth, |_, _, value| {
+                nearest_level(value as i32, depth)
+            });
+            let threshold = Threshold::new(depth);
+            assert_eq!(threshold.dither(&image, width, height, stride), expected);
+        }
+    }
+
+    #[test]
+    fn dithers_odd_frame_sizes() {
+        let (width, height, stride) = (101, 101, 104);
+        let image = gray_image(width, height, stride);
+        let pixels = (width * height) as usize;
+
+        for depth in DEPTHS {
+            let size = depth.frame_size(width, height);
+            assert_eq!(size, (pixels * depth.bits() as usize).div_ceil(8));
+
+            for method in DitherMethod::VARIANTS {
+                let method: DitherMethod = method.parse().unwrap();
+                let mut dither = TemporalDither::new(method.create(depth), depth, 8);
+
+                // Second frame keeps pixels of the first one, up to the last one
+                for _ in 0..2 {
+                    let frame = dither.dither(&image, width, height, stride);
+                    assert_eq!(frame.len(), size, "{} {:?}", method, depth);
+                    assert!(depth.unpack(&frame)[pixels..].iter().all(|&gray| gray == 0));
+                }
+            }
+
+            let expected = pack_pixels(&image, width, height, stride, depth, |_, _, value| {
+                nearest_level(value as i32, depth)
+            });
+            let threshold = Threshold::new(depth);
+            assert_eq!(threshold.dither(&image, width, height, stride), expected);
+        }
+    }
+
+    /// Gray levels of all pixels in the frame.
+    fn levels(frame: &[u8], depth: BitDepth) -> Vec<u8> {
+        let gray_step = 255 / (depth.levels() - 1) as u8;
+        depth
+            .unpack(frame)
+            .iter()
+            .map(|gray| gray / gray_step)
+            .collect()
+    }
+
+    #[test]
+    fn keeps_levels_within_hysteresis() {
+        // Gray values 94 to 110 are level 6 with 4bpp, 43 to 127 level 1 with 2bpp. Pixels up to
+        // 10 away from the start keep their level, even when they crossed into another one.
+        for (depth, start, level, image, expected) in [
+            (
+                BitDepth::Four,
+                94,
+                6,
+                [93, 83, 104, 105, 94, 84, 110, 111],
+                [6, 5, 6, 6, 6, 6, 6, 7],
+            ),
+            (
+                BitDepth::Two,
+                43,
+                1,
+                [42, 30, 43, 53, 127, 128, 42, 32],
+                [1, 0, 1, 1, 1, 2, 1, 0],
+            ),
+        ] {
+            let mut dither = TemporalDither::new(Box::new(Threshold::new(depth)), depth, 10);
+            let frame = dither.dither(&[start; 8], 8, 1, 8);
+            assert_eq!(levels(&frame, depth), [level; 8]);
+
+            let frame = dither.dither(&image, 8, 1, 8);
+            assert_eq!(levels(&frame, depth), expected, "{:?}", depth);
+        }
+    }
+
+    #[test]
+    fn updates_reference_of_changed_pixels() {
+        let depth = BitDepth::Four;
+        let mut dither = TemporalDither::new(Box::new(Threshold::new(depth)), depth, 10);
+        dither.dither(&[94, 94], 2, 1, 2);
+
+        // First pixel moves to level 5 with 83 as its new reference, the second one stays
+        let frame = dither.dither(&[83, 93], 2, 1, 2);
+        assert_eq!(levels(&frame, depth), [5, 6]);
+
+        // 73 is within the hysteresis of the new reference, but not of the old one
+        let frame = dither.dither(&[73, 93], 2, 1, 2);
+        assert_eq!(levels(&frame, depth), [5, 6]);
+        let frame = dither.dither(&[72, 93], 2, 1, 2);
+        assert_eq!(levels(&frame, depth), [4, 6]);
+    }
+
+    #[test]
+    fn starts_over_after_reset() {
+        let depth = BitDepth::Four;
+        let mut dither = TemporalDither::new(Box::new(Threshold::new(depth)), depth, 10);
+        dither.dither(&[94, 94], 2, 1, 2);
+
+        dither.reset();
+        let frame = dither.dither(&[93, 94], 2, 1, 2);
+        assert_eq!(levels(&frame, depth), [5, 6]);
+    }
+
+    #[test]
+    fn thresholds_to_nearest_gray_level() {
+        let image: Vec<u8> = (0..=255).collect();
+        for depth in DEPTHS {
+            let frame = Threshold::new(depth).dither(&image, 256, 1, 256);
+            let step = 255 / (depth.levels() - 1) as i32;
+
+            for (value, gray) in image.iter().zip(depth.unpack(&frame)) {
+                assert!(
+                    (*value as i32 - gray as i32).abs() <= step / 2,
+                    "{:?}",
+                    depth
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn error_diffusion_keeps_average_brightness() {
+        let (width, height) = (64, 64);
+        for method in [
+            DitherMethod::FloydSteinberg,
+            DitherMethod::Atkinson,
+            DitherMethod::SierraLite,
+        ] {
+            let frame =
+                method
+                    .create(BitDepth::One)
+                    .dither(&[64; 64 * 64], width, height, width as usize);
+            let white = frame.iter().map(|byte| byte.count_ones()).sum::<u32>();
+
+            // A quarter of the pixels should be white, Atkinson loses some of the error
+            let share = white as f32 / (width * height) as f32;
+            assert!((0.15..0.3).contains(&share), "{}: {}", method, share);
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Errors which can occur when talking to the IT8951 controller.
+
+use std::fmt;
+
+/// Result of all operations talking to the IT8951 controller.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Errors which can occur when talking to the IT8951 controller.
+#[derive(Debug)]
+pub enum Error {
+    /// No IT8951 device is connected via USB.
+    DeviceNotFound,
+
+    /// Device was found but we are not allowed to talk to it.
+    PermissionDenied,
+
+    /// Device did not respond in time.
+    Timeout,
+
+    /// Any other error reported by the USB stack.
+    Usb(rusb::Error),
+
+    /// Status wrapper did not have the expected length of 13 bytes.
+    InvalidStatusLength(usize),
+
+    /// Status wrapper did not contain the expected "USBS" signature.
+    InvalidSignature([u8; 4]),
+
+    /// Status wrapper belongs to another command than the one we sent.
+    TagMismatch {
+        /// Tag of the command we sent.
+        expected: u32,
+
+        /// Tag of the status wrapper we received.
+        actual: u32,
+    },
+
+    /// Device reported that the command failed.
+    CommandFailed {
+        /// Number of bytes which have not been processed.
+        data_residue: u32,
+    },
+
+    /// Device and host disagree about the state of the transfer, the device got reset.
+    Phase,
+
+    /// Device did not process all data of the transfer.
+    DataResidue(u32),
+
+    /// Data sent to or received from the device could not be (de-)serialized.
+    Serialization(bincode::Error),
+
+    /// Image dimensions or position do not fit onto the panel.
+    InvalidGeometry(String),
+
+    /// Data does not fit into the address space of the controller memory.
+    MemoryOverflow {
+        /// Address the data should have been written to.
+        address: u32,
+
+        /// Number of bytes which should have been written.
+        length: usize,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::DeviceNotFound => write!(
+                f,
+                "no IT8951 device found, make sure the display is connected via USB"
+            ),
+            Error::PermissionDenied => write!(
+                f,
+                "permission denied when opening IT8951 device, make sure the udev rule for \
+                vendor 048d is installed (see README)"
+            ),
+            Error::Timeout => write!(
+                f,
+                "IT8951 device did not respond in time, try reconnecting the display"
+            ),
+            Error::Usb(error) => write!(f, "usb error: {}", error),
+            Error::InvalidStatusLength(length) => {
+                write!(f, "invalid command status length of {} bytes", length)
+            }
+            Error::InvalidSignature(signature) => {
+                write!(f, "invalid command status signature {:x?}", signature)
+            }
+            Error::TagMismatch { expected, actual } => write!(
+                f,
+                "command status tag {} does not match command tag {}",
+                actual, expected
+            ),
+            Error::CommandFailed { data_residue } => write!(
+                f,
+                "device reported failed command ({} bytes not processed)",
+                data_residue
+            ),
+            Error::Phase => write!(f, "device reported phase error"),
+            Error::DataResidue(data_residue) => {
+                write!(f, "device did not process {} bytes", data_residue)
+            }
+            Error::Serialization(error) => write!(f, "invalid data: {}", error),
+            Error::InvalidGeometry(message) => write!(f, "invalid geometry: {}", message),
+            Error::MemoryOverflow { address, length } => write!(
+                f,
+                "{} bytes written at 0x{:x} exceed the controller memory",
+                length, address
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Usb(error) => Some(error),
+            Error::Serialization(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<rusb::Error> for Error {
+    fn from(error: rusb::Error) -> Self {
+        match error {
+            rusb::Error::NotFound | rusb::Error::NoDevice => Error::DeviceNotFound,
+            rusb::Error::Access => Error::PermissionDenied,
+            rusb::Error::Timeout => Error::Timeout,
+            _ => Error::Usb(error),
+        }
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(error: bincode::Error) -> Self {
+        Error::Serialization(error)
+    }
+}
@@ -0,0 +1,199 @@
+use crate::video::GrayFrame;
+
+/// Number of tiles in every direction the local contrast is equalized in.
+const LOCAL_CONTRAST_TILES: usize = 8;
+
+/// Filters emphasizing the details of the video, so fine structures survive dithering.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Filters {
+    /// Strength of the unsharp mask, 0.0 disables it.
+    pub sharpen: f32,
+
+    /// Radius of the blur the unsharp mask compares every pixel with.
+    pub sharpen_radius: u32,
+
+    /// Strength of the edge emphasis, 0.0 disables it.
+    pub edges: f32,
+
+    /// Clip limit of the local contrast enhancement (CLAHE), 0.0 disables it. Higher values allow
+    /// more contrast but also amplify noise.
+    pub local_contrast: f32,
+}
+
+impl Filters {
+    /// Apply all enabled filters to the frame.
+    pub fn apply(&self, frame: &mut GrayFrame) {
+        if self.sharpen == 0.0 && self.edges == 0.0 && self.local_contrast == 0.0 {
+            return;
+        }
+
+        // Work on rows without padding
+        let (width, height) = (frame.width as usize, frame.height as usize);
+        if frame.stride != width {
+            frame.data = frame
+                .data
+                .chunks(frame.stride)
+                .take(height)
+                .flat_map(|row| &row[..width])
+                .copied()
+                .collect();
+            frame.stride = width;
+        }
+
+        if self.local_contrast > 0.0 {
+            equalize_locally(&mut frame.data, width, height, self.local_contrast);
+        }
+
+        if self.sharpen > 0.0 {
+            unsharp_mask(
+                &mut frame.data,
+                width,
+                height,
+                self.sharpen_radius as usize,
+                self.sharpen,
+            );
+        }
+
+        if self.edges > 0.0 {
+            emphasize_edges(&mut frame.data, width, height, self.edges);
+        }
+    }
+}
+
+/// Increase the difference of every pixel to its blurred surrounding.
+fn unsharp_mask(pixels: &mut [u8], width: usize, height: usize, radius: usize, amount: f32) {
+    let mut horizontal = vec![0; pixels.len()];
+    let mut blurred = vec![0; pixels.len()];
+    blur_lines(pixels, &mut horizontal, height, width, width, 1, radius);
+    blur_lines(&horizontal, &mut blurred, width, height, 1, width, radius);
+
+    for (pixel, &blurred) in pixels.iter_mut().zip(&blurred) {
+        let difference = *pixel as f32 - blurred as f32;
+        *pixel = (*pixel as f32 + difference * amount)
+            .round()
+            .clamp(0.0, 255.0) as u8;
+    }
+}
+
+/// Box blur along lines of pixels, which are `line_step` apart and have their pixels `step` apart.
+/// Pixels outside of the image repeat the one at the border.
+fn blur_lines(
+    input: &[u8],
+    output: &mut [u8],
+    lines: usize,
+    length: usize,
+    line_step: usize,
+    step: usize,
+    radius: usize,
+) {
+    let window = (2 * radius + 1) as u32;
+    let radius = radius as isize;
+
+    for line in 0..lines {
+        let start = line * line_step;
+        let at = |index: isize| input[start + index.clamp(0, length as isize - 1) as usize * step];
+
+        let mut sum: u32 = (-radius..=radius).map(|index| at(index) as u32).sum();
+        for index in 0..length {
+            output[start + index * step] = ((sum + window / 2) / window) as u8;
+
+            let index = index as isize;
+            sum += at(index + radius + 1) as u32;
+            sum -= at(index - radius) as u32;
+        }
+    }
+}
+
+/// Increase the difference of every pixel to its direct neighbours (Laplacian sharpening).
+fn emphasize_edges(pixels: &mut [u8], width: usize, height: usize, amount: f32) {
+    let input = pixels.to_vec();
+    let at = |x: usize, y: usize| input[y * width + x] as f32;
+
+    for y in 0..height {
+        for x in 0..width {
+            let center = at(x, y);
+            let neighbours = at(x.saturating_sub(1), y)
+                + at((x + 1).min(width - 1), y)
+                + at(x, y.saturating_sub(1))
+                + at(x, (y + 1).min(height - 1));
+
+            let edge = 4.0 * center - neighbours;
+            pixels[y * width + x] = (center + edge * amount).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+/// Contrast limited adaptive histogram equalization (CLAHE): Equalize the histogram of every tile
+/// of the image, limiting how much contrast gets added, and blend between neighbouring tiles.
+fn equalize_locally(pixels: &mut [u8], width: usize, height: usize, clip_limit: f32) {
+    let tile_width = width.div_ceil(LOCAL_CONTRAST_TILES);
+    let tile_height = height.div_ceil(LOCAL_CONTRAST_TILES);
+    let columns = width.div_ceil(tile_width);
+    let rows = height.div_ceil(tile_height);
+
+    // Find mapping of gray values for every tile
+    let mut mappings = vec![[0u8; 256]; columns * rows];
+    for row in 0..rows {
+        for column in 0..columns {
+            let (left, top) = (column * tile_width, row * tile_height);
+            let (right, bottom) = (
+                (left + tile_width).min(width),
+                (top + tile_height).min(height),
+            );
+
+            let mut histogram = [0u32; 256];
+            for y in top..bottom {
+                for &value in &pixels[y * width + left..y * width + right] {
+                    histogram[value as usize] += 1;
+                }
+            }
+
+            // Cut off peaks of the histogram and spread them across all gray values
+            let count = ((right - left) * (bottom - top)) as u32;
+            let limit = ((clip_limit * count as f32 / 256.0) as u32).max(1);
+            let mut excess = 0;
+            for bin in histogram.iter_mut() {
+                if *bin > limit {
+                    excess += *bin - limit;
+                    *bin = limit;
+                }
+            }
+            for (index, bin) in histogram.iter_mut().enumerate() {
+                *bin += excess / 256 + u32::from((index as u32) < excess % 256);
+            }
+
+            let mapping = &mut mappings[row * columns + column];
+            let mut sum = 0;
+            for (value, bin) in histogram.iter().enumerate() {
+                sum += bin;
+                mapping[value] = (sum as f32 * 255.0 / count as f32).round() as u8;
+            }
+        }
+    }
+
+    // Blend between the mappings of the tiles whose centers are closest to every pixel
+    let neighbours = |position: usize, size: usize, tiles: usize| {
+        let tile = ((position as f32 + 0.5) / size as f32 - 0.5).max(0.0);
+        let first = (tile as usize).min(tiles - 1);
+        let second = (first + 1).min(tiles - 1);
+        (first, second, tile - first as f32)
+    };
+    let column_neighbours: Vec<_> = (0..width)
+        .map(|x| neighbours(x, tile_width, columns))
+        .collect();
+
+    for y in 0..height {
+        let (top, bottom, weight_y) = neighbours(y, tile_height, rows);
+        let weight_y = weight_y.min(1.0);
+
+        for (x, &(left, right, weight_x)) in column_neighbours.iter().enumerate() {
+            let weight_x = weight_x.min(1.0);
+            let value = pixels[y * width + x] as usize;
+            let mapped = |row: usize, column: usize| mappings[row * columns + column][value] as f32;
+
+            let upper = mapped(top, left) * (1.0 - weight_x) + mapped(top, right) * weight_x;
+            let lower = mapped(bottom, left) * (1.0 - weight_x) + mapped(bottom, right) * weight_x;
+            pixels[y * width + x] = (upper * (1.0 - weight_y) + lower * weight_y).round() as u8;
+        }
+    }
+}
@@ -0,0 +1,214 @@
+//! Decide when the panel should be cleaned up, as fast display modes leave ghosting behind.
+
+use crate::dither::BitDepth;
+
+/// Side length of the square regions transitions are counted in.
+const REGION_SIZE: u32 = 64;
+
+/// Share of changed pixels below which a frame is considered calm enough for a cleanup.
+const CALM_SHARE: f32 = 0.02;
+
+/// Track the pixel transitions done in a fast display mode and decide when to clean the panel up
+/// with a slower mode.
+///
+/// A cleanup is done when the transitions accumulated in any region of the panel cross the
+/// threshold, but only once the content calmed down so it does not happen in the middle of the
+/// action. Scene cuts are cleaned up right away as the whole picture changes anyways, and the
+/// panel is cleaned up at the latest after the maximum interval of frames.
+pub struct GhostTracker {
+    width: u32,
+    height: u32,
+    depth: BitDepth,
+    columns: u32,
+
+    /// Number of transitions of every region since the last cleanup.
+    transitions: Vec<u64>,
+
+    /// Number of frames since the last cleanup.
+    frames: usize,
+
+    threshold: f32,
+    max_interval: usize,
+}
+
+impl GhostTracker {
+    /// Track frames of the given dimensions.
+    ///
+    /// The threshold is the number of transitions per pixel in a region which require a cleanup.
+    pub fn new(
+        width: u32,
+        height: u32,
+        depth: BitDepth,
+        threshold: f32,
+        max_interval: usize,
+    ) -> Self {
+        let columns = width.div_ceil(REGION_SIZE);
+        let rows = height.div_ceil(REGION_SIZE);
+
+        Self {
+            width,
+            height,
+            depth,
+            columns,
+            transitions: vec![0; (columns * rows) as usize],
+            frames: 0,
+            threshold,
+            max_interval,
+        }
+    }
+
+    /// Record the transitions from the previous to the current packed frame, which might start a
+    /// new scene. Returns true if the current frame should be displayed with a cleaning mode.
+    pub fn next_frame(&mut self, previous: Option<&[u8]>, current: &[u8], scene_cut: bool) -> bool {
+        let previous = match previous {
+            Some(previous) => previous,
+            None => return self.clean(),
+        };
+
+        let changed = self.count_transitions(previous, current);
+        self.frames += 1;
+
+        if scene_cut || self.frames >= self.max_interval {
+            return self.clean();
+        }
+
+        let share = changed as f32 / (self.width * self.height) as f32;
+        if share <= CALM_SHARE && self.max_transitions() >= self.threshold {
+            return self.clean();
+        }
+
+        false
+    }
+
+    /// Highest number of transitions per pixel in any region.
+    fn max_transitions(&self) -> f32 {
+        let region_pixels = (REGION_SIZE * REGION_SIZE) as f32;
+        self.transitions
+            .iter()
+            .map(|&transitions| transitions as f32 / region_pixels)
+            .fold(0.0, f32::max)
+    }
+
+    /// Add changed pixels to their regions and return the total number of changed pixels.
+    fn count_transitions(&mut self, previous: &[u8], current: &[u8]) -> u64 {
+        let bits = self.depth.bits() as usize;
+        let pixels_per_byte = 8 / bits;
+        let pixels = self.width as usize * self.height as usize;
+        let pixel_mask = (1u8 << bits) - 1;
+        let mut total = 0;
+
+        for (index, (a, b)) in previous.iter().zip(current).enumerate() {
+            let difference = a ^ b;
+            if difference == 0 {
+                continue;
+            }
+
+            // Add every pixel in this byte which got a different value to its region, rows do not
+            // necessarily start with a new byte
+            for pixel in 0..pixels_per_byte {
+                let pixel_index = index * pixels_per_byte + pixel;
+                if difference >> (pixel * bits) & pixel_mask == 0 || pixel_index >= pixels {
+                    continue;
+                }
+
+                let x = (pixel_index % self.width as usize) as u32;
+                let y = (pixel_index / self.width as usize) as u32;
+                let region = (y / REGION_SIZE) * self.columns + x / REGION_SIZE;
+                self.transitions[region as usize] += 1;
+                total += 1;
+            }
+        }
+
+        total
+    }
+
+    fn clean(&mut self) -> bool {
+        self.transitions.fill(0);
+        self.frames = 0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One region of 1bpp pixels.
+    const SIZE: u32 = REGION_SIZE;
+
+    fn frame(value: u8) -> Vec<u8> {
+        vec![value; BitDepth::One.frame_size(SIZE, SIZE)]
+    }
+
+    #[test]
+    fn cleans_up_first_frame() {
+        let mut tracker = GhostTracker::new(SIZE, SIZE, BitDepth::One, 1.0, 100);
+        assert!(tracker.next_frame(None, &frame(0x00), false));
+    }
+
+    #[test]
+    fn waits_for_calm_frame_above_threshold() {
+        let mut tracker = GhostTracker::new(SIZE, SIZE, BitDepth::One, 1.5, 100);
+        let (black, white) = (frame(0x00), frame(0xff));
+
+        // Every pixel changes in every frame, that is too much action for a cleanup
+        assert!(!tracker.next_frame(Some(&black), &white, false));
+        assert!(!tracker.next_frame(Some(&white), &black, false));
+        assert!(!tracker.next_frame(Some(&black), &white, false));
+
+        // Few changed pixels, the threshold was crossed before
+        let mut calm = white.clone();
+        calm[0] = 0x00;
+        assert!(tracker.next_frame(Some(&white), &calm, false));
+
+        // Transitions start over after the cleanup
+        assert!(!tracker.next_frame(Some(&calm), &white, false));
+    }
+
+    #[test]
+    fn keeps_calm_frames_below_threshold() {
+        let mut tracker = GhostTracker::new(SIZE, SIZE, BitDepth::One, 1.5, 100);
+        let (black, white) = (frame(0x00), frame(0xff));
+        let mut calm = white.clone();
+        calm[0] = 0x00;
+
+        assert!(!tracker.next_frame(Some(&black), &white, false));
+        assert!(!tracker.next_frame(Some(&white), &calm, false));
+        assert!(!tracker.next_frame(Some(&calm), &white, false));
+    }
+
+    #[test]
+    fn cleans_up_scene_cuts() {
+        let mut tracker = GhostTracker::new(SIZE, SIZE, BitDepth::One, 1.0, 100);
+        let black = frame(0x00);
+
+        assert!(!tracker.next_frame(Some(&black), &black, false));
+        assert!(tracker.next_frame(Some(&black), &black, true));
+    }
+
+    #[test]
+    fn cleans_up_after_maximum_interval() {
+        let mut tracker = GhostTracker::new(SIZE, SIZE, BitDepth::One, 1.0, 3);
+        let black = frame(0x00);
+
+        for _ in 0..2 {
+            assert!(!tracker.next_frame(Some(&black), &black, false));
+            assert!(!tracker.next_frame(Some(&black), &black, false));
+            assert!(tracker.next_frame(Some(&black), &black, false));
+        }
+    }
+
+    #[test]
+    fn counts_transitions_of_unaligned_rows() {
+        // Rows of 3x3 pixels are spread across two bytes, the padding bits are ignored
+        let mut tracker = GhostTracker::new(3, 3, BitDepth::One, 1.0, 10);
+        assert_eq!(tracker.count_transitions(&[0x00, 0x00], &[0xff, 0xff]), 9);
+        assert_eq!(tracker.transitions, vec![9]);
+
+        let mut tracker = GhostTracker::new(65, 1, BitDepth::Two, 1.0, 10);
+        let mut current = vec![0; BitDepth::Two.frame_size(65, 1)];
+        current[16] = 0b11;
+        assert_eq!(tracker.count_transitions(&[0; 17], &current), 1);
+        assert_eq!(tracker.transitions, vec![0, 1]);
+    }
+}
@@ -0,0 +1,39 @@
+//! Talk to IT8951-controlled e-paper displays via USB.
+//!
+//! The [`API`] sends commands to the controller to upload images into its memory and display them
+//! on the panel. By default it talks to a device connected via USB, but any [`Transport`] can be
+//! used, for example the simulated [`MockDevice`] when no display is plugged in.
+//!
+//! The [`dither`] module converts 8bpp grayscale images into packed 1bpp, 2bpp or 4bpp frames
+//! which can be uploaded to the controller.
+//!
+//! ```no_run
+//! use it8951::{Mode, API};
+//!
+//! # fn main() -> it8951::Result<()> {
+//! let mut api = API::connect(800, 600)?;
+//! let address = api.get_system_info().image_buffer_base;
+//! api.set_memory(address, &vec![0xff; 800 * 600])?;
+//! api.display_image(address, Mode::GC16)?;
+//! # Ok(())
+//! # }
+//! ```
+#![warn(missing_docs)]
+
+pub mod api;
+mod clock;
+pub mod dirty;
+pub mod dither;
+pub mod error;
+pub mod ghost;
+pub mod mock;
+pub mod player;
+pub mod ring;
+pub mod transport;
+pub mod usb;
+
+pub use api::{Area, Mode, SystemInfo, API};
+pub use error::{Error, Result};
+pub use mock::MockDevice;
+pub use transport::Transport;
+pub use usb::{DeviceSelector, ScsiOverUsbConnection, UsbDevice};
@@ -1,36 +1,54 @@
-mod api;
-mod usb;
+mod cache;
+mod filter;
+mod orientation;
+mod output;
+mod scale;
+mod tone;
+mod video;
+mod wall;
 
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-
-use anyhow::Result;
-use ffmpeg_next::format::{input, Pixel};
-use ffmpeg_next::media::Type;
-use ffmpeg_next::software::scaling::{context::Context, flag::Flags};
-use ffmpeg_next::util::frame::video::Video;
+use std::sync::mpsc;
+
+use anyhow::{ensure, Context, Result};
 use structopt::StructOpt;
 use tokio::sync::broadcast;
-use tokio::sync::mpsc;
 use tokio::task;
 
-use api::{Mode, API};
+use it8951::dither::{BitDepth, BitOrder, BitmapFormat, DitherMethod, TemporalDither};
+use it8951::player::{image_size, width_alignment, Player, PlayerOptions, Progress, VideoFrame};
+use it8951::{DeviceSelector, MockDevice, Transport, UsbDevice, API};
 
-/// Single video frame to be displayed on e-paper. It contains multiple bytes where every bit of it
-/// represents a pixel (1 = white, 0 = black).
-type Frame = Vec<u8>;
+use cache::{FrameCacheReader, FrameCacheWriter};
+use filter::Filters;
+use orientation::{Flip, Orientation, Rotation};
+use output::Recorder;
+use scale::{Rect, ScaleAlgorithm, ScaleMode, Scaling};
+use tone::Tone;
+use video::{Processing, VideoDecoder};
+use wall::Tile;
 
 #[derive(Debug, StructOpt)]
 #[structopt(
     name = "it8951-video-display",
     about = "Play videos on IT8951-controlled e-paper displays"
 )]
-struct Opt {
-    /// Video file which will be displayed.
-    #[structopt(parse(from_os_str))]
-    input: PathBuf,
+enum Opt {
+    /// Dither video frames ahead of time and store them in a file which can be played later.
+    Prepare(PrepareOpt),
+
+    /// Play a video or a prepared file on the e-paper display.
+    Play(PlayOpt),
 
+    /// Play a video spanning across multiple e-paper displays.
+    Wall(WallOpt),
+
+    /// List all IT8951 controllers connected via USB.
+    Devices,
+}
+
+#[derive(Debug, StructOpt)]
+struct VideoOpt {
     /// Width of video on display.
     #[structopt(short = "w", long = "width", default_value = "1856")]
     width: u32,
@@ -43,282 +61,775 @@ struct Opt {
     #[structopt(short = "t", long = "take", default_value = "5")]
     take: usize,
 
-    /// Paint in GL16 mode every nth frame.
+    /// Dithering algorithm converting the grayscale video into black and white pixels.
+    #[structopt(
+        short = "d",
+        long = "dither",
+        default_value = "bayer",
+        possible_values = &DitherMethod::VARIANTS
+    )]
+    dither: DitherMethod,
+
+    /// Bits per pixel, more bits allow more gray levels but are slower to transfer and display.
+    #[structopt(
+        short = "b",
+        long = "bpp",
+        default_value = "1",
+        possible_values = &["1", "2", "4"]
+    )]
+    bits_per_pixel: BitDepth,
+
+    /// Keep dithered pixels of the previous frame unless their gray value changed by more than
+    /// this (0 to 255), which reduces flicker in static areas. 0 dithers every frame on its own.
+    #[structopt(long = "stable", default_value = "0")]
+    stable: u8,
+
+    /// Treat frames as a new scene when this share (0.0 to 1.0) of their luminance histogram
+    /// changed. The panel gets cleaned up in GL16 mode on every new scene.
+    #[structopt(long = "scene-cut", default_value = "0.3")]
+    scene_cut: f32,
+
+    /// How the video is fitted into width and height when their aspect ratios differ: Show the
+    /// whole video with bars around it, fill everything by cropping or stretch it.
+    #[structopt(
+        short = "s",
+        long = "scale",
+        default_value = "fit",
+        possible_values = &ScaleMode::VARIANTS
+    )]
+    scale: ScaleMode,
+
+    /// Algorithm used to scale the video.
+    #[structopt(
+        long = "scaler",
+        default_value = "bilinear",
+        possible_values = &ScaleAlgorithm::VARIANTS
+    )]
+    scaler: ScaleAlgorithm,
+
+    /// Only show this part of the video, given as "x,y,width,height" in pixels of the original
+    /// video.
+    #[structopt(long = "crop")]
+    crop: Option<Rect>,
+
+    /// Gray value (0 to 255) of the bars around the video when it does not fill everything.
+    #[structopt(long = "background", default_value = "255")]
+    background: u8,
+
+    /// Rotate the video clockwise by 0, 90, 180 or 270 degrees, for panels which are mounted
+    /// rotated. Width and height stay the dimensions on the display.
+    #[structopt(long = "rotate", default_value = "0")]
+    rotate: Rotation,
+
+    /// Mirror the video horizontally (h) or vertically (v).
+    #[structopt(long = "flip")]
+    flip: Option<Flip>,
+
+    /// Brighten up (up to 1.0) or darken (down to -1.0) the video.
+    #[structopt(long = "brightness", default_value = "0.0", allow_hyphen_values = true)]
+    brightness: f32,
+
+    /// Increase (above 1.0) or decrease (below 1.0) the contrast of the video.
+    #[structopt(long = "contrast", default_value = "1.0")]
+    contrast: f32,
+
+    /// Gamma correction, values above 1.0 brighten up dark parts of the video.
+    #[structopt(long = "gamma", default_value = "1.0")]
+    gamma: f32,
+
+    /// Gray values (0 to 255) which become black and white, for example "16,235". Everything in
+    /// between gets stretched.
+    #[structopt(long = "levels", parse(try_from_str = parse_levels))]
+    levels: Option<(u8, u8)>,
+
+    /// Find black and white point of every frame by itself.
+    #[structopt(long = "auto-levels")]
+    auto_levels: bool,
+
+    /// Share (0.0 to 1.0) of the levels of the previous frames kept in every frame, avoiding
+    /// sudden jumps in brightness with `--auto-levels`.
+    #[structopt(long = "auto-levels-smoothing", default_value = "0.8")]
+    auto_levels_smoothing: f32,
+
+    /// Strength of the unsharp mask, sharpening details which get lost when scaling the video
+    /// down. Try values between 0.5 and 2.0.
+    #[structopt(long = "sharpen", default_value = "0.0")]
+    sharpen: f32,
+
+    /// Radius of the unsharp mask in pixels, larger values sharpen coarser details.
+    #[structopt(long = "sharpen-radius", default_value = "2")]
+    sharpen_radius: u32,
+
+    /// Strength of the edge emphasis, outlining edges so they stand out after dithering. Try
+    /// values between 0.2 and 1.0.
+    #[structopt(long = "edges", default_value = "0.0")]
+    edges: f32,
+
+    /// Enhance the contrast of every part of the video by itself (CLAHE). The value limits how
+    /// much contrast gets added, try values between 2.0 and 4.0.
+    #[structopt(long = "local-contrast", default_value = "0.0")]
+    local_contrast: f32,
+}
+
+impl VideoOpt {
+    /// Make sure all options are within their bounds.
+    fn validate(&self) -> Result<()> {
+        ensure!(
+            self.take > 0 && self.take < 25,
+            "take needs to be between 1 and 24"
+        );
+        ensure!(
+            (-1.0..=1.0).contains(&self.brightness),
+            "brightness needs to be between -1.0 and 1.0"
+        );
+        ensure!(self.contrast >= 0.0, "contrast can not be negative");
+        ensure!(self.gamma > 0.0, "gamma needs to be above 0.0");
+        ensure!(
+            self.levels.is_none_or(|(black, white)| black < white),
+            "black level needs to be below white level"
+        );
+        ensure!(
+            !(self.auto_levels && self.levels.is_some()),
+            "levels can not be set when they are found automatically"
+        );
+        ensure!(
+            (0.0..1.0).contains(&self.auto_levels_smoothing),
+            "auto levels smoothing needs to be between 0.0 and 1.0"
+        );
+        ensure!(
+            self.sharpen >= 0.0 && self.edges >= 0.0 && self.local_contrast >= 0.0,
+            "filter strengths can not be negative"
+        );
+        ensure!(
+            self.sharpen_radius > 0,
+            "sharpen radius needs to be at least 1"
+        );
+        ensure!(
+            self.width % width_alignment(self.bits_per_pixel) == 0,
+            "width needs to be a multiple of {} with {} bits per pixel",
+            width_alignment(self.bits_per_pixel),
+            self.bits_per_pixel.bits()
+        );
+
+        Ok(())
+    }
+
+    /// Dithering algorithm for frames of the given bit depth.
+    fn create_dither(&self, depth: BitDepth) -> TemporalDither {
+        TemporalDither::new(self.dither.create(depth), depth, self.stable)
+    }
+
+    /// How decoded frames get processed before dithering.
+    fn processing(&self) -> Processing {
+        Processing {
+            scene_cut: self.scene_cut,
+            scaling: Scaling {
+                mode: self.scale,
+                algorithm: self.scaler,
+                crop: self.crop,
+                background: self.background,
+            },
+            orientation: Orientation {
+                rotation: self.rotate,
+                flip: self.flip,
+            },
+            tone: Tone {
+                brightness: self.brightness,
+                contrast: self.contrast,
+                gamma: self.gamma,
+                levels: self.levels,
+                auto_levels: self.auto_levels,
+                smoothing: self.auto_levels_smoothing,
+            },
+            filters: Filters {
+                sharpen: self.sharpen,
+                sharpen_radius: self.sharpen_radius,
+                edges: self.edges,
+                local_contrast: self.local_contrast,
+            },
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+struct PrepareOpt {
+    /// Video file which will be prepared.
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+
+    /// File the prepared frames will be written to.
+    #[structopt(parse(from_os_str))]
+    output: PathBuf,
+
+    #[structopt(flatten)]
+    video: VideoOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct PlayOpt {
+    /// Video or prepared file which will be displayed.
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+
+    /// Dimensions and frame rate of video, ignored for prepared files.
+    #[structopt(flatten)]
+    video: VideoOpt,
+
+    /// Paint in GL16 mode at least every nth frame.
+    #[structopt(short = "g", long = "ghost", default_value = "32")]
+    ghost: usize,
+
+    /// Paint in GL16 mode as soon as the content calmed down, after a region of the panel went
+    /// through this many transitions per pixel.
+    #[structopt(long = "ghost-threshold", default_value = "3.0")]
+    ghost_threshold: f32,
+
+    /// VCOM value.
+    #[structopt(short = "v", long = "vcom", default_value = "-1.58")]
+    vcom: f32,
+
+    /// Format of 1bpp images in the controller memory.
+    #[structopt(flatten)]
+    bitmap: BitmapOpt,
+
+    /// Display frames in sync with the video clock, dropping late frames.
+    #[structopt(short = "r", long = "realtime")]
+    realtime: bool,
+
+    /// Simulate the IT8951 controller in memory instead of talking to a device via USB.
+    #[structopt(long = "simulate")]
+    simulate: bool,
+
+    /// Write what the simulated panel shows after every frame into a PNG sequence, an animated GIF
+    /// or a Y4M video, chosen by the file extension. Implies `--simulate`.
+    #[structopt(short = "o", long = "output", parse(from_os_str))]
+    output: Option<PathBuf>,
+
+    /// Share (0.0 to 1.0) of the previous gray value which remains visible after every frame
+    /// displayed in a fast mode, to simulate ghosting.
+    #[structopt(long = "ghosting", default_value = "0.0")]
+    ghosting: f32,
+
+    /// Only upload and refresh the areas which changed since the previous frame.
+    #[structopt(short = "p", long = "partial")]
+    partial: bool,
+
+    /// Position of the top left corner of the video on the display, for example "0,0". The video
+    /// is centered by default.
+    #[structopt(long = "position", parse(try_from_str = parse_position))]
+    position: Option<(u32, u32)>,
+
+    /// Display to play the video on, selected by "bus:address" or serial number (see `devices`).
+    #[structopt(long = "device")]
+    device: Option<DeviceSelector>,
+}
+
+#[derive(Debug, StructOpt)]
+struct BitmapOpt {
+    /// Order of the pixels in every byte of 1bpp images, some controller firmwares expect the
+    /// first pixel in the highest bit.
+    #[structopt(long = "bit-order", default_value = "lsb", possible_values = &["lsb", "msb"])]
+    bit_order: BitOrder,
+
+    /// Use set bits of 1bpp images for black pixels instead of white ones, for controller firmwares
+    /// with the opposite polarity.
+    #[structopt(long = "invert")]
+    invert: bool,
+
+    /// Gray value (0 to 255) black pixels are displayed with in 1bpp mode, for example 80 for a
+    /// dark gray. The controller only uses the upper 4 bits.
+    #[structopt(long = "bitmap-foreground", default_value = "0")]
+    bitmap_foreground: u8,
+
+    /// Gray value (0 to 255) white pixels are displayed with in 1bpp mode. The controller only uses
+    /// the upper 4 bits.
+    #[structopt(long = "bitmap-background", default_value = "240")]
+    bitmap_background: u8,
+}
+
+impl BitmapOpt {
+    /// Format of 1bpp images chosen by these options.
+    fn format(&self) -> BitmapFormat {
+        BitmapFormat {
+            bit_order: self.bit_order,
+            invert: self.invert,
+            foreground: self.bitmap_foreground,
+            background: self.bitmap_background,
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+struct WallOpt {
+    /// Video file which will be displayed.
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+
+    /// Dimensions of every tile and frame rate of video.
+    #[structopt(flatten)]
+    video: VideoOpt,
+
+    /// Panel of the video wall, for example "device=1:4,column=1,row=0,rotate=90,x=-4,y=2".
+    /// Repeat this for every panel.
+    #[structopt(long = "tile", required = true, number_of_values = 1)]
+    tiles: Vec<Tile>,
+
+    /// Width of the bezel between two panels next to each other, in pixels.
+    #[structopt(long = "bezel-x", default_value = "0")]
+    bezel_x: u32,
+
+    /// Height of the bezel between two panels above each other, in pixels.
+    #[structopt(long = "bezel-y", default_value = "0")]
+    bezel_y: u32,
+
+    /// Paint in GL16 mode at least every nth frame.
     #[structopt(short = "g", long = "ghost", default_value = "32")]
     ghost: usize,
 
+    /// Paint in GL16 mode as soon as the content calmed down, after a region of the panel went
+    /// through this many transitions per pixel.
+    #[structopt(long = "ghost-threshold", default_value = "3.0")]
+    ghost_threshold: f32,
+
     /// VCOM value.
     #[structopt(short = "v", long = "vcom", default_value = "-1.58")]
     vcom: f32,
+
+    /// Format of 1bpp images in the controller memory.
+    #[structopt(flatten)]
+    bitmap: BitmapOpt,
+
+    /// Simulate the IT8951 controllers in memory instead of talking to devices via USB.
+    #[structopt(long = "simulate")]
+    simulate: bool,
 }
 
-/// Helper to apply dithering to a field of pixels.
-struct ThresholdMatrix {
-    nx: u32,
-    ny: u32,
-    matrix: Vec<u8>,
+/// Where the frames to be displayed are coming from.
+enum FrameSource {
+    /// Decode and dither video file on-the-fly.
+    Video,
+
+    /// Read already prepared frames from file.
+    Cache(FrameCacheReader),
 }
 
-impl ThresholdMatrix {
-    fn new() -> Self {
-        let power_of_two = 8;
-        let side = 2_u32.pow(power_of_two);
-        let num_elements = side * side;
-        let norm_factor = 255_f32 / (num_elements as f32);
-
-        let mut matrix: Vec<u8> = Vec::new();
-        for x in 0..side {
-            for y in 0..side {
-                let xc = x ^ y;
-                let yc = y;
-                let mut v = 0;
-
-                for p in (0..power_of_two).rev() {
-                    let bit_idx = 2 * (power_of_two - p - 1);
-                    v |= ((yc >> p) & 1) << bit_idx;
-                    v |= ((xc >> p) & 1) << (bit_idx + 1);
-                }
+/// Panel dimensions of the simulated controller (Waveshare 7.8inch e-Paper HAT).
+const SIMULATED_PANEL_WIDTH: u32 = 1872;
+const SIMULATED_PANEL_HEIGHT: u32 = 1404;
 
-                matrix.push((v as f32 * norm_factor) as u8);
+/// Number of frames which can be queued up for the display thread.
+const FRAME_BUFFER_SIZE: usize = 32;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    match Opt::from_args() {
+        Opt::Prepare(opt) => {
+            opt.video.validate()?;
+            prepare(opt)
+        }
+        Opt::Play(opt) => {
+            opt.video.validate()?;
+            ensure!(
+                opt.vcom < 0.0 && opt.vcom >= -5.0,
+                "VCOM value needs to be between -5.0 and 0.0"
+            );
+            ensure!(opt.ghost > 0, "ghost needs to be at least 1");
+            ensure!(
+                (0.0..=1.0).contains(&opt.ghosting),
+                "ghosting needs to be between 0.0 and 1.0"
+            );
+            ensure!(
+                !(opt.realtime && opt.output.is_some()),
+                "realtime playback can not be written to an output file"
+            );
+
+            // Prepared files define the dimensions of the video themselves
+            let (source, width, height) = if FrameCacheReader::is_frame_cache(&opt.input)? {
+                let reader = FrameCacheReader::open(&opt.input)?;
+                let header = *reader.header();
+                (FrameSource::Cache(reader), header.width, header.height)
+            } else {
+                (FrameSource::Video, opt.video.width, opt.video.height)
+            };
+
+            // Connect to IT8951 controlled display
+            if opt.simulate || opt.output.is_some() {
+                let device = MockDevice::new(SIMULATED_PANEL_WIDTH, SIMULATED_PANEL_HEIGHT)
+                    .with_ghosting(opt.ghosting)
+                    .with_bit_order(opt.bitmap.bit_order);
+                let recorder = match &opt.output {
+                    Some(path) => Some(Recorder::create(
+                        path,
+                        device.clone(),
+                        SIMULATED_PANEL_WIDTH,
+                        SIMULATED_PANEL_HEIGHT,
+                        output_frame_rate(&opt, &source)?,
+                    )?),
+                    None => None,
+                };
+                let api = API::new(device, width, height)?;
+                play(api, opt, source, recorder).await
+            } else {
+                let selector = opt.device.clone().unwrap_or(DeviceSelector::Any);
+                let device = UsbDevice::find(&selector)?;
+                let api = API::connect_device(&device, width, height)?;
+                play(api, opt, source, None).await
             }
         }
+        Opt::Wall(opt) => {
+            opt.video.validate()?;
+            ensure!(
+                opt.vcom < 0.0 && opt.vcom >= -5.0,
+                "VCOM value needs to be between -5.0 and 0.0"
+            );
+            ensure!(opt.ghost > 0, "ghost needs to be at least 1");
+
+            let layout = wall::Layout::new(
+                &opt.tiles,
+                opt.video.width,
+                opt.video.height,
+                opt.bezel_x,
+                opt.bezel_y,
+            );
+
+            // Rotated panels show the tile height as their width
+            let alignment = width_alignment(opt.video.bits_per_pixel);
+            for tile in &opt.tiles {
+                let (width, _) = layout.panel_size(tile);
+                ensure!(
+                    width % alignment == 0,
+                    "height needs to be a multiple of {} for rotated tiles",
+                    alignment
+                );
+            }
 
-        Self {
-            nx: side,
-            ny: side,
-            matrix,
+            // Connect to all IT8951 controlled displays
+            if opt.simulate {
+                let apis = opt
+                    .tiles
+                    .iter()
+                    .map(|tile| {
+                        let device = MockDevice::new(SIMULATED_PANEL_WIDTH, SIMULATED_PANEL_HEIGHT)
+                            .with_bit_order(opt.bitmap.bit_order);
+                        let (width, height) = layout.panel_size(tile);
+                        API::new(device, width, height)
+                    })
+                    .collect::<it8951::Result<Vec<_>>>()?;
+                wall::play(apis, opt).await
+            } else {
+                let apis = wall::connect(&opt.tiles, &layout)?;
+                wall::play(apis, opt).await
+            }
         }
+        Opt::Devices => devices(),
     }
+}
 
-    fn look_up(&self, x: u32, y: u32) -> u8 {
-        let j = x % self.nx;
-        let i = y % self.ny;
-        let idx: usize = (i * self.nx + j)
-            .try_into()
-            .expect("i * side_length + j does not fit into usize");
+/// Print all connected IT8951 controllers together with their panel dimensions.
+fn devices() -> Result<()> {
+    let devices = UsbDevice::list()?;
+    if devices.is_empty() {
+        println!("No IT8951 device found");
+    }
 
-        self.matrix[idx]
+    for device in devices {
+        // Devices which are in use by another process can not be asked about their panel
+        match device.read_system_info() {
+            Ok(system_info) => println!(
+                "{}: panel {}x{}",
+                device, system_info.width, system_info.height
+            ),
+            Err(error) => println!("{}: {}", device, error),
+        }
     }
+
+    Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let opt = Opt::from_args();
-    assert!(opt.take > 0 && opt.take < 25);
-    assert!(opt.vcom < 0.0 && opt.vcom >= -5.0);
-    assert!(opt.ghost > 0);
+/// Decode and dither the video and store all frames in a file.
+fn prepare(opt: PrepareOpt) -> Result<()> {
+    let mut decoder = VideoDecoder::open(
+        &opt.input,
+        opt.video.width,
+        opt.video.height,
+        opt.video.take,
+        &opt.video.processing(),
+    )
+    .context("Failed opening video file")?;
+    let mut dither = opt.video.create_dither(opt.video.bits_per_pixel);
+
+    let frame_rate = decoder.frame_rate();
+    let mut writer = FrameCacheWriter::create(
+        &opt.output,
+        opt.video.width,
+        opt.video.height,
+        opt.video.bits_per_pixel,
+        (
+            frame_rate.numerator() as u32,
+            frame_rate.denominator() as u32,
+        ),
+        opt.video.take as u32,
+    )?;
+
+    loop {
+        let mut frames = Vec::new();
+        let decoding = decoder.decode_next(|frame| frames.push(frame.dither(&mut dither)))?;
+
+        for frame in frames {
+            writer.write_frame(&frame)?;
+        }
+
+        if !decoding {
+            break;
+        }
+    }
 
-    // Connect to IT8951 controlled display
-    let mut api = API::connect(opt.width, opt.height)?;
+    let header = writer.finish()?;
+
+    println!(
+        "Stored {} frames ({}x{}, {}bpp) in {}",
+        header.frame_count,
+        header.width,
+        header.height,
+        header.bits_per_pixel,
+        opt.output.display()
+    );
+
+    Ok(())
+}
+
+/// Frame rate of the displayed frames, taking only every nth frame of the video into account.
+fn output_frame_rate(opt: &PlayOpt, source: &FrameSource) -> Result<(u32, u32)> {
+    match source {
+        FrameSource::Video => {
+            let frame_rate =
+                VideoDecoder::probe_frame_rate(&opt.input).context("Failed opening video file")?;
+            Ok((
+                frame_rate.numerator() as u32,
+                frame_rate.denominator() as u32 * opt.video.take as u32,
+            ))
+        }
+        FrameSource::Cache(reader) => {
+            let header = reader.header();
+            Ok((header.frame_rate_num, header.frame_rate_den * header.take))
+        }
+    }
+}
+
+/// Display the video frame by frame on the e-paper display. The recorder writes the content of a
+/// simulated panel after every frame.
+async fn play<T: Transport + Send + 'static>(
+    api: API<T>,
+    opt: PlayOpt,
+    source: FrameSource,
+    mut recorder: Option<Recorder>,
+) -> Result<()> {
+    let (width, height, depth) = match &source {
+        FrameSource::Video => (opt.video.width, opt.video.height, opt.video.bits_per_pixel),
+        FrameSource::Cache(reader) => {
+            let header = reader.header();
+            (header.width, header.height, header.bit_depth())
+        }
+    };
 
     // Get system information
     let system_info = api.get_system_info();
+    let (panel_width, panel_height) = (system_info.width, system_info.height);
     let image_buffer_base = system_info.image_buffer_base;
 
-    // Calculate byte size of each 1bpp image
-    let image_size = (opt.width * opt.height) / 8;
+    // Use image buffer to store multiple frames at once
+    let mut player = Player::new(
+        api,
+        PlayerOptions {
+            width,
+            height,
+            depth,
+            position: opt.position,
+            bitmap: opt.bitmap.format(),
+            ghost_threshold: opt.ghost_threshold,
+            ghost_interval: opt.ghost,
+            realtime: opt.realtime,
+            partial: opt.partial,
+        },
+        opt.vcom,
+    )?;
 
     println!(
         r#"
       VCOM value: {}
 Panel Dimensions: {}x{}
 Video Dimensions: {}x{}
+  Bits per Pixel: {}
   Buffer Address: 0x{:x}
+    Buffer Slots: {}
       Image size: {} bytes
         "#,
         opt.vcom,
-        system_info.width,
-        system_info.height,
-        opt.width,
-        opt.height,
+        panel_width,
+        panel_height,
+        width,
+        height,
+        depth.bits(),
         image_buffer_base,
-        image_size
+        player.slots(),
+        image_size(depth, width, height)
     );
 
-    // Make sure the target sizes fit on the display
-    assert!(system_info.width >= opt.width);
-    assert!(system_info.height >= opt.height);
-
-    // Set VCOM value
-    api.set_vcom(opt.vcom)?;
-
-    // Remember register value for later
-    let reg = api.get_memory_register_value(0x1800_1138)?;
-
-    // Enable 1bit drawing and image pitch mode
-    // 0000 0000 0000 0110 0000 0000 0000 0000
-    // |         |     ^^  |         |
-    // 113B      113A      1139      1138
-    api.set_memory_register_value(0x1800_1138, reg | 1 << 18 | 1 << 17)?;
-
-    // Set bitmap mode color definition (0 - set black(0x00), 1 - set white(0xf0))
-    api.set_memory_register_value(0x1800_1250, 0xf0 | 0x00 << 8)?;
-
-    // Set image pitch width
-    api.set_memory_register_value(0x1800_124c, opt.width / 8 / 4)?;
-
     // Establish communication channels between both threads
     let (shutdown_tx, mut shutdown_rx) = broadcast::channel::<bool>(1);
-    let (frame_tx, mut frame_rx) = mpsc::unbounded_channel::<Frame>();
-    let video_finished = Arc::new(AtomicBool::new(false));
-    let video_finished_tx = video_finished.clone();
-
-    // Spawn the first thread: It will decode the video, convert every frame into the right format
-    // and send it over to the display thread.
-    let video_task = task::spawn_blocking(move || {
-        // Open video stream from file
-        let mut context_video = input(&opt.input).expect("Failed opening video file");
-
-        let input = context_video
-            .streams()
-            .best(Type::Video)
-            .ok_or(ffmpeg_next::Error::StreamNotFound)
-            .expect("Could not find video stream in file");
-        let video_stream_index = input.index();
-
-        // Prepare video decoder which should rescale frames to target size and make them grayscale
-        let context_decoder =
-            ffmpeg_next::codec::context::Context::from_parameters(input.parameters()).unwrap();
-        let mut decoder = context_decoder.decoder().video().unwrap();
-
-        let mut scaler = Context::get(
-            decoder.format(),
-            decoder.width(),
-            decoder.height(),
-            Pixel::GRAY8,
-            opt.width,
-            opt.height,
-            Flags::BILINEAR,
-        )
-        .unwrap();
-
-        let threshold_matrix = ThresholdMatrix::new();
-        let mut frame_counter = 0;
-
-        let mut receive_and_process_decoded_frames =
-            |decoder: &mut ffmpeg_next::decoder::Video| -> Result<(), ffmpeg_next::Error> {
-                let mut decoded = Video::empty();
-
-                while decoder.receive_frame(&mut decoded).is_ok() {
-                    // Decode next frame
-                    let mut frame = Video::empty();
-                    scaler.run(&decoded, &mut frame)?;
-
-                    // Only take every nth frame from video
-                    if frame_counter % opt.take == 0 {
-                        // Encode as grayscale image
-                        let data_8bpp = frame.data(0);
-                        let width = frame.width();
-                        let height = frame.height();
-
-                        // Dither and convert to raw format, representing black (0) or white (1) pixels
-                        // in an array
-                        let mut data_1bpp: Frame = vec![0b0000_0000; (width * height / 8) as usize];
-                        for y in 0..height {
-                            for x in 0..width {
-                                let index_8bpp = (y * width) + x;
-                                let index_1bpp = ((y * width) + x) / 8;
-
-                                // Set bit to 1 in byte if dithering returned a black pixel
-                                if data_8bpp[index_8bpp as usize] > threshold_matrix.look_up(x, y) {
-                                    data_1bpp[index_1bpp as usize] =
-                                        data_1bpp[index_1bpp as usize] | 2_u8.pow(index_8bpp % 8);
-                                };
-                            }
-                        }
-                        frame_tx.send(data_1bpp).unwrap();
+    let (frame_tx, frame_rx) = mpsc::sync_channel::<VideoFrame>(FRAME_BUFFER_SIZE);
+    let realtime = opt.realtime;
+
+    // Spawn the first thread: It will decode the video (or read the prepared file), convert every
+    // frame into the right format and send it over to the display thread.
+    let video_task = task::spawn_blocking(move || -> Result<()> {
+        match source {
+            FrameSource::Video => {
+                let mut decoder = VideoDecoder::open(
+                    &opt.input,
+                    width,
+                    height,
+                    opt.video.take,
+                    &opt.video.processing(),
+                )
+                .context("Failed opening video file")?;
+                let mut dither = opt.video.create_dither(depth);
+
+                // Decode packets until the video ended or we cancelled the process
+                let mut cancelled = false;
+                while !cancelled {
+                    if let Ok(true) = shutdown_rx.try_recv() {
+                        return Ok(());
                     }
 
-                    frame_counter += 1;
-                }
+                    let decoding = decoder
+                        .decode_next(|frame| {
+                            // Display thread stopped when sending fails
+                            let frame = frame.dither(&mut dither);
+                            cancelled |= frame_tx.send(frame).is_err();
+                        })
+                        .context("Failed decoding video")?;
 
-                Ok(())
-            };
-
-        // Decode packets until the video ended or we cancelled the process
-        let mut packet_iter = context_video.packets();
-        let mut cancelled: bool = false;
+                    if !decoding {
+                        break;
+                    }
+                }
 
-        loop {
-            if let Ok(true) = shutdown_rx.try_recv() {
-                cancelled = true;
-                break;
+                if cancelled {
+                    return Ok(());
+                }
             }
+            FrameSource::Cache(reader) => {
+                for frame in reader {
+                    if let Ok(true) = shutdown_rx.try_recv() {
+                        return Ok(());
+                    }
 
-            match packet_iter.next() {
-                Some((stream, packet)) => {
-                    if stream.index() == video_stream_index {
-                        decoder.send_packet(&packet).unwrap();
-                        receive_and_process_decoded_frames(&mut decoder).unwrap();
+                    let frame = frame.context("Failed reading prepared file")?;
+                    if frame_tx.send(frame).is_err() {
+                        return Ok(());
                     }
                 }
-                None => {
-                    break;
-                }
             }
         }
 
-        decoder.send_eof().unwrap();
-
-        if !cancelled {
-            receive_and_process_decoded_frames(&mut decoder).unwrap();
-
-            video_finished_tx.store(true, Ordering::SeqCst);
+        // Let the display thread know that all frames have been sent
+        drop(frame_tx);
 
-            // .. keep thread running even when it is done! This allows us to select the join handle
-            // with tokio in case this thread panics and exists
-            loop {
-                if let Ok(true) = shutdown_rx.try_recv() {
-                    break;
-                }
+        // .. keep thread running even when it is done! This allows us to select the join handle
+        // with tokio in case this thread panics and exists
+        loop {
+            if let Ok(true) = shutdown_rx.try_recv() {
+                break;
             }
         }
+
+        Ok(())
     });
 
     // Spawn the second thread: It will receive the frames and display them on the e-paper device.
     let mut shutdown_rx_panel = shutdown_tx.subscribe();
-    let panel_task = task::spawn_blocking(move || {
-        let mut frame_counter = 0;
+    let mut panel_task = task::spawn_blocking(move || -> Result<()> {
         loop {
             if let Ok(true) = shutdown_rx_panel.try_recv() {
-                api.clear_display().unwrap();
                 break;
             }
 
-            if let Ok(frame) = frame_rx.try_recv() {
-                // Load images into buffer
-                api.set_memory(image_buffer_base, &frame).unwrap();
-
-                // ... so we can finally display the images!
-                if frame_counter % opt.ghost == 0 {
-                    // Sometimes draw image properly (this is slower) to avoid too much ghosting
-                    api.display_image(image_buffer_base, Mode::GL16).unwrap();
-                } else {
-                    // ... and display the others with a faster mode
-                    api.display_image(image_buffer_base, Mode::A2).unwrap();
+            match player.step(&frame_rx)? {
+                Progress::Displayed => {
+                    if let Some(recorder) = &mut recorder {
+                        recorder.record()?;
+                    }
                 }
-
-                frame_counter += 1;
-            } else if video_finished.load(Ordering::SeqCst) {
+                Progress::Waiting => {}
                 // Finish when video is done AND buffer is empty
-                api.clear_display().unwrap();
-                break;
+                Progress::Finished => break,
             }
         }
+
+        player.clear_display()?;
+
+        if realtime {
+            println!("Dropped {} late frames", player.dropped_frames());
+        }
+
+        if let Some(recorder) = recorder {
+            recorder.finish()?;
+        }
+
+        Ok(())
     });
 
     // Run this until [CTRL] + [C] got pressed or something went wrong
+    let mut video_result = Ok(Ok(()));
+    let mut panel_result = None;
     tokio::select! {
-        _ = video_task => (),
-        _ = panel_task => (),
+        result = video_task => {
+            video_result = result;
+        },
+        result = &mut panel_task => {
+            panel_result = Some(result);
+        },
         _ = tokio::signal::ctrl_c() => {
             println!("\nExit program ..");
         },
     }
 
-    if let Err(_) = shutdown_tx.send(true) {
+    if shutdown_tx.send(true).is_err() {
         // Ignore error
     }
 
+    // Wait until the display thread cleared the panel and restored the controller registers
+    let panel_result = match panel_result {
+        Some(result) => result,
+        None => panel_task.await,
+    };
+
+    // Report what went wrong in either of the threads
+    panel_result??;
+    video_result??;
+
     Ok(())
 }
+
+/// Parse position on the display given as "x,y".
+fn parse_position(value: &str) -> Result<(u32, u32), String> {
+    let invalid = || format!("invalid position '{}', use x,y", value);
+    let (x, y) = value.split_once(',').ok_or_else(invalid)?;
+
+    Ok((
+        x.trim().parse().map_err(|_| invalid())?,
+        y.trim().parse().map_err(|_| invalid())?,
+    ))
+}
+
+/// Parse black and white level given as "black,white".
+fn parse_levels(value: &str) -> Result<(u8, u8), String> {
+    let invalid = || format!("invalid levels '{}', use black,white", value);
+    let (black, white) = value.split_once(',').ok_or_else(invalid)?;
+
+    Ok((
+        black.trim().parse().map_err(|_| invalid())?,
+        white.trim().parse().map_err(|_| invalid())?,
+    ))
+}
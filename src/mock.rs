@@ -0,0 +1,501 @@
+//! Simulated IT8951 controller, useful to run and test things without a display.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use bincode::config::Options;
+
+use crate::api::{
+    DisplayArea, Mode, SystemInfo, BGVR_REG, CUSTOMER_CMD, DISPLAY_AREA_CMD, FAST_WRITE_CMD,
+    GET_SYS_INFO_CMD, PITCH_REG, PMIC_CONTROL_CMD, READ_REG_CMD, UP1SR_1BPP_FLAG, UP1SR_PITCH_FLAG,
+    UP1SR_REG, WRITE_REG_CMD,
+};
+use crate::dither::BitOrder;
+use crate::error::{Error, Result};
+use crate::transport::Transport;
+
+/// Address of the simulated update buffer.
+const UPDATE_BUFFER_BASE: u32 = 0x0010_0000;
+
+/// Address of the simulated image buffer.
+const IMAGE_BUFFER_BASE: u32 = 0x0020_0000;
+
+/// Gray value of a pixel after the panel got cleared.
+const WHITE: u8 = 0xff;
+
+/// Display update which was requested by the host.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DisplayUpdate {
+    /// Memory address the image was loaded from.
+    pub address: u32,
+
+    /// E-panel display mode.
+    pub mode: Mode,
+
+    /// Left position of the updated area.
+    pub x: u32,
+
+    /// Top position of the updated area.
+    pub y: u32,
+
+    /// Width of the updated area.
+    pub width: u32,
+
+    /// Height of the updated area.
+    pub height: u32,
+}
+
+/// Internal state of the simulated controller.
+struct State {
+    system_info: SystemInfo,
+
+    /// Register map, registers which have never been written to read as zero.
+    registers: HashMap<u32, u32>,
+
+    /// Controller memory, starting at address zero and ending after the image buffer.
+    memory: Vec<u8>,
+
+    /// 8bpp gray values of what is currently shown on the panel.
+    panel: Vec<u8>,
+
+    /// VCOM value which got set via the PMIC command.
+    vcom: Option<u16>,
+
+    /// All display updates in the order they were requested.
+    display_updates: Vec<DisplayUpdate>,
+
+    /// Share of the previous gray value which remains after an update in a fast display mode.
+    ghosting: f32,
+
+    /// Order the pixels of 1bpp images are read from every byte.
+    bit_order: BitOrder,
+}
+
+/// Simulated IT8951 controller, keeping its memory, registers and panel content in memory.
+///
+/// This allows running everything without a device being plugged in. The handle can be cloned to
+/// inspect the state of the controller while it is used by the API.
+#[derive(Clone)]
+pub struct MockDevice {
+    state: Arc<Mutex<State>>,
+}
+
+impl MockDevice {
+    /// Simulate a controller with a panel of the given dimensions.
+    pub fn new(width: u32, height: u32) -> Self {
+        let system_info = SystemInfo {
+            standard_cmd_no: 0,
+            extended_cmd_no: 0,
+            signature: u32::from_be_bytes(*b"8951"),
+            version: 0x0001_0002,
+            width,
+            height,
+            update_buffer_base: UPDATE_BUFFER_BASE,
+            image_buffer_base: IMAGE_BUFFER_BASE,
+            temperature_no: 0,
+            mode: Mode::A2,
+            frame_count: [0; 8],
+            num_img_buf: 1,
+            reserved: [0; 9],
+        };
+
+        // Image buffer has space for exactly one 8bpp image
+        let memory_size = IMAGE_BUFFER_BASE + width * height;
+
+        Self {
+            state: Arc::new(Mutex::new(State {
+                system_info,
+                registers: HashMap::new(),
+                memory: vec![0; memory_size as usize],
+                panel: vec![WHITE; (width * height) as usize],
+                vcom: None,
+                display_updates: Vec::new(),
+                ghosting: 0.0,
+                bit_order: BitOrder::LsbFirst,
+            })),
+        }
+    }
+
+    /// Simulate the ghosting fast display modes (A2, DU and DU4) leave behind: The given share
+    /// (0.0 to 1.0) of the previous gray value remains visible after every update in these modes,
+    /// until the pixel gets updated in a cleaning mode.
+    pub fn with_ghosting(self, strength: f32) -> Self {
+        self.state().ghosting = strength.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Simulate a controller firmware reading the pixels of 1bpp images in the given order from
+    /// every byte.
+    pub fn with_bit_order(self, bit_order: BitOrder) -> Self {
+        self.state().bit_order = bit_order;
+        self
+    }
+
+    /// Return current value of a register.
+    pub fn register(&self, address: u32) -> u32 {
+        *self.state().registers.get(&address).unwrap_or(&0)
+    }
+
+    /// Return a copy of the controller memory.
+    pub fn memory(&self, address: u32, length: usize) -> Vec<u8> {
+        let start = address as usize;
+        self.state().memory[start..start + length].to_vec()
+    }
+
+    /// Return a copy of the 8bpp gray values currently shown on the panel.
+    pub fn panel(&self) -> Vec<u8> {
+        self.state().panel.clone()
+    }
+
+    /// Return the VCOM value which was set, for example 1580 for -1.58V.
+    pub fn vcom(&self) -> Option<u16> {
+        self.state().vcom
+    }
+
+    /// Return all display updates requested so far.
+    pub fn display_updates(&self) -> Vec<DisplayUpdate> {
+        self.state().display_updates.clone()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("mock state poisoned")
+    }
+}
+
+impl Transport for MockDevice {
+    fn read_command_raw(&mut self, command: &[u8; 16], length: usize) -> Result<Vec<u8>> {
+        let state = self.state();
+
+        if command[0] != CUSTOMER_CMD {
+            return Err(command_failed(length));
+        }
+
+        let data = match command[6] {
+            GET_SYS_INFO_CMD => bincode::options()
+                .with_big_endian()
+                .with_fixint_encoding()
+                .serialize(&state.system_info)?,
+            READ_REG_CMD => {
+                let address = command_address(command);
+                let value = *state.registers.get(&address).unwrap_or(&0);
+                value.to_be_bytes().to_vec()
+            }
+            _ => {
+                return Err(command_failed(length));
+            }
+        };
+
+        if data.len() != length {
+            return Err(Error::DataResidue(length.abs_diff(data.len()) as u32));
+        }
+
+        Ok(data)
+    }
+
+    fn write_command_raw(&mut self, command: &[u8; 16], data: &[u8]) -> Result<()> {
+        let mut state = self.state();
+
+        if command[0] != CUSTOMER_CMD {
+            return Err(command_failed(data.len()));
+        }
+
+        match command[6] {
+            WRITE_REG_CMD => {
+                let value: [u8; 4] = data.try_into().map_err(|_| command_failed(data.len()))?;
+                state
+                    .registers
+                    .insert(command_address(command), u32::from_be_bytes(value));
+            }
+            PMIC_CONTROL_CMD => {
+                // Only set VCOM when requested
+                if command[9] == 0x01 {
+                    state.vcom = Some(u16::from_be_bytes([command[7], command[8]]));
+                }
+            }
+            FAST_WRITE_CMD => {
+                let length = u16::from_be_bytes([command[7], command[8]]) as usize;
+                let start = command_address(command) as usize;
+                if length != data.len() || start + length > state.memory.len() {
+                    return Err(command_failed(data.len()));
+                }
+
+                state.memory[start..start + length].copy_from_slice(data);
+            }
+            DISPLAY_AREA_CMD => {
+                let area: DisplayArea = bincode::options()
+                    .with_big_endian()
+                    .with_fixint_encoding()
+                    .deserialize(data)
+                    .map_err(|_| command_failed(data.len()))?;
+                state.display_area(&area)?;
+            }
+            _ => {
+                return Err(command_failed(data.len()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl State {
+    /// Render the given area from memory onto the panel.
+    fn display_area(&mut self, area: &DisplayArea) -> Result<()> {
+        let panel_width = self.system_info.width;
+        if area.x + area.width > panel_width || area.y + area.height > self.system_info.height {
+            return Err(Error::CommandFailed { data_residue: 0 });
+        }
+
+        self.display_updates.push(DisplayUpdate {
+            address: area.address,
+            mode: area.display_mode,
+            x: area.x,
+            y: area.y,
+            width: area.width,
+            height: area.height,
+        });
+
+        let registers = &self.registers;
+        let register = |address| *registers.get(&address).unwrap_or(&0);
+
+        for y in 0..area.height {
+            for x in 0..area.width {
+                let value = if area.display_mode == Mode::INIT {
+                    WHITE
+                } else if register(UP1SR_REG) & UP1SR_1BPP_FLAG != 0 {
+                    // Every bit represents a pixel, rows are aligned by the pitch register
+                    let pitch = register(PITCH_REG) * 4;
+                    let index = (area.address + y * pitch + x / 8) as usize;
+                    let byte = *self.memory.get(index).ok_or_else(|| command_failed(0))?;
+                    let colors = register(BGVR_REG);
+                    let bit = match self.bit_order {
+                        BitOrder::LsbFirst => x % 8,
+                        BitOrder::MsbFirst => 7 - x % 8,
+                    };
+
+                    if byte & (1 << bit) != 0 {
+                        (colors & 0xff) as u8
+                    } else {
+                        ((colors >> 8) & 0xff) as u8
+                    }
+                } else {
+                    // Rows are aligned by the pitch register when pitch mode is enabled
+                    let pitch = if register(UP1SR_REG) & UP1SR_PITCH_FLAG != 0 {
+                        register(PITCH_REG) * 4
+                    } else {
+                        area.width
+                    };
+                    let index = (area.address + y * pitch + x) as usize;
+                    *self.memory.get(index).ok_or_else(|| command_failed(0))?
+                };
+
+                let panel_index = ((area.y + y) * panel_width + area.x + x) as usize;
+                self.panel[panel_index] = match area.display_mode {
+                    Mode::A2 | Mode::DU | Mode::DU4 => {
+                        let previous = self.panel[panel_index] as f32;
+                        (value as f32 + (previous - value as f32) * self.ghosting).round() as u8
+                    }
+                    _ => value,
+                };
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Extract the big-endian address from bytes 2 to 5 of a customer command.
+fn command_address(command: &[u8; 16]) -> u32 {
+    u32::from_be_bytes([command[2], command[3], command[4], command[5]])
+}
+
+/// Simulate the device reporting a failed command.
+fn command_failed(length: usize) -> Error {
+    Error::CommandFailed {
+        data_residue: length as u32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{Area, API};
+
+    const WIDTH: u32 = 64;
+    const HEIGHT: u32 = 8;
+
+    fn connect() -> (MockDevice, API<MockDevice>) {
+        let device = MockDevice::new(WIDTH, HEIGHT);
+        let api = API::new(device.clone(), WIDTH, HEIGHT).expect("mock accepts handshake");
+        (device, api)
+    }
+
+    #[test]
+    fn reports_system_info() {
+        let (_, api) = connect();
+        let system_info = api.get_system_info();
+
+        assert_eq!(system_info.signature, u32::from_be_bytes(*b"8951"));
+        assert_eq!((system_info.width, system_info.height), (WIDTH, HEIGHT));
+        assert_eq!(system_info.image_buffer_base, IMAGE_BUFFER_BASE);
+    }
+
+    #[test]
+    fn rejects_image_larger_than_panel() {
+        let device = MockDevice::new(WIDTH, HEIGHT);
+        assert!(matches!(
+            API::new(device, WIDTH + 1, HEIGHT),
+            Err(Error::InvalidGeometry(_))
+        ));
+    }
+
+    #[test]
+    fn writes_and_reads_registers() {
+        let (device, mut api) = connect();
+
+        api.set_memory_register_value(PITCH_REG, 0x1234_5678)
+            .unwrap();
+        assert_eq!(
+            api.get_memory_register_value(PITCH_REG).unwrap(),
+            0x1234_5678
+        );
+        assert_eq!(device.register(PITCH_REG), 0x1234_5678);
+
+        // Original value gets restored when the API is dropped
+        drop(api);
+        assert_eq!(device.register(PITCH_REG), 0);
+    }
+
+    #[test]
+    fn sets_vcom() {
+        let (device, mut api) = connect();
+        assert_eq!(device.vcom(), None);
+
+        api.set_vcom(-1.58).unwrap();
+        assert_eq!(device.vcom(), Some(1580));
+    }
+
+    #[test]
+    fn writes_memory_at_address() {
+        let (device, mut api) = connect();
+        let address = IMAGE_BUFFER_BASE + 100;
+
+        api.set_memory(address, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(device.memory(address - 1, 6), vec![0, 1, 2, 3, 4, 0]);
+    }
+
+    #[test]
+    fn displays_8bpp_image() {
+        let (device, mut api) = connect();
+        let image: Vec<u8> = (0..WIDTH * HEIGHT).map(|index| index as u8).collect();
+
+        api.set_memory(IMAGE_BUFFER_BASE, &image).unwrap();
+        api.display_image(IMAGE_BUFFER_BASE, Mode::GC16).unwrap();
+
+        assert_eq!(device.panel(), image);
+        assert_eq!(
+            device.display_updates(),
+            vec![DisplayUpdate {
+                address: IMAGE_BUFFER_BASE,
+                mode: Mode::GC16,
+                x: 0,
+                y: 0,
+                width: WIDTH,
+                height: HEIGHT,
+            }]
+        );
+    }
+
+    #[test]
+    fn displays_area_of_8bpp_image() {
+        let (device, mut api) = connect();
+        api.set_memory(IMAGE_BUFFER_BASE, &[0x00; 4 * 2]).unwrap();
+        api.display_area(IMAGE_BUFFER_BASE, Area::new(8, 2, 4, 2), Mode::DU)
+            .unwrap();
+
+        let panel = device.panel();
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let inside = (8..12).contains(&x) && (2..4).contains(&y);
+                let expected = if inside { 0x00 } else { WHITE };
+                assert_eq!(
+                    panel[(y * WIDTH + x) as usize],
+                    expected,
+                    "pixel {},{}",
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn displays_1bpp_image() {
+        let (device, mut api) = connect();
+        api.set_memory_register_value(UP1SR_REG, UP1SR_1BPP_FLAG | UP1SR_PITCH_FLAG)
+            .unwrap();
+        api.set_memory_register_value(BGVR_REG, 0xf0 | 0x50 << 8)
+            .unwrap();
+        api.set_memory_register_value(PITCH_REG, WIDTH / 8 / 4)
+            .unwrap();
+
+        // First pixel of every byte is set (lowest bit), all others cleared
+        api.set_memory(
+            IMAGE_BUFFER_BASE,
+            &vec![0x01; (WIDTH * HEIGHT / 8) as usize],
+        )
+        .unwrap();
+        api.display_image(IMAGE_BUFFER_BASE, Mode::A2).unwrap();
+
+        for (index, &value) in device.panel().iter().enumerate() {
+            let expected = if index % 8 == 0 { 0xf0 } else { 0x50 };
+            assert_eq!(value, expected, "pixel {}", index);
+        }
+    }
+
+    #[test]
+    fn reads_1bpp_image_in_bit_order() {
+        let device = MockDevice::new(WIDTH, HEIGHT).with_bit_order(BitOrder::MsbFirst);
+        let mut api = API::new(device.clone(), WIDTH, HEIGHT).unwrap();
+        api.set_memory_register_value(UP1SR_REG, UP1SR_1BPP_FLAG | UP1SR_PITCH_FLAG)
+            .unwrap();
+        api.set_memory_register_value(BGVR_REG, 0xf0).unwrap();
+        api.set_memory_register_value(PITCH_REG, WIDTH / 8 / 4)
+            .unwrap();
+
+        api.set_memory(
+            IMAGE_BUFFER_BASE,
+            &vec![0x80; (WIDTH * HEIGHT / 8) as usize],
+        )
+        .unwrap();
+        api.display_image(IMAGE_BUFFER_BASE, Mode::A2).unwrap();
+
+        let panel = device.panel();
+        assert_eq!(&panel[..9], &[0xf0, 0, 0, 0, 0, 0, 0, 0, 0xf0]);
+    }
+
+    #[test]
+    fn clears_display_to_white() {
+        let (device, mut api) = connect();
+        api.set_memory(IMAGE_BUFFER_BASE, &[0x00; (WIDTH * HEIGHT) as usize])
+            .unwrap();
+        api.display_image(IMAGE_BUFFER_BASE, Mode::GC16).unwrap();
+
+        api.clear_display().unwrap();
+        assert!(device.panel().iter().all(|&value| value == WHITE));
+    }
+
+    #[test]
+    fn leaves_ghosting_in_fast_modes() {
+        let device = MockDevice::new(WIDTH, HEIGHT).with_ghosting(0.5);
+        let mut api = API::new(device.clone(), WIDTH, HEIGHT).unwrap();
+        api.set_memory(IMAGE_BUFFER_BASE, &[0x00; (WIDTH * HEIGHT) as usize])
+            .unwrap();
+
+        api.display_image(IMAGE_BUFFER_BASE, Mode::A2).unwrap();
+        assert_eq!(device.panel()[0], 0x80);
+
+        api.display_image(IMAGE_BUFFER_BASE, Mode::GC16).unwrap();
+        assert_eq!(device.panel()[0], 0x00);
+    }
+}
@@ -0,0 +1,134 @@
+use std::str::FromStr;
+
+use crate::video::GrayFrame;
+
+/// Rotation of the image shown on a panel, clockwise in degrees.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Rotation {
+    /// Keep the image as it is.
+    None,
+
+    /// Rotate a quarter turn to the right.
+    Clockwise90,
+
+    /// Turn the image upside down.
+    Clockwise180,
+
+    /// Rotate a quarter turn to the left.
+    Clockwise270,
+}
+
+impl Rotation {
+    /// Size of the image before it got rotated into the given size.
+    pub fn source_size(&self, width: u32, height: u32) -> (u32, u32) {
+        match self {
+            Rotation::None | Rotation::Clockwise180 => (width, height),
+            Rotation::Clockwise90 | Rotation::Clockwise270 => (height, width),
+        }
+    }
+
+    /// Position of a pixel of the rotated image in the image before it got rotated, which has the
+    /// given size.
+    pub fn source_position(&self, x: i64, y: i64, width: i64, height: i64) -> (i64, i64) {
+        match self {
+            Rotation::None => (x, y),
+            Rotation::Clockwise90 => (y, height - 1 - x),
+            Rotation::Clockwise180 => (width - 1 - x, height - 1 - y),
+            Rotation::Clockwise270 => (width - 1 - y, x),
+        }
+    }
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "0" => Ok(Rotation::None),
+            "90" => Ok(Rotation::Clockwise90),
+            "180" => Ok(Rotation::Clockwise180),
+            "270" => Ok(Rotation::Clockwise270),
+            _ => Err(format!(
+                "invalid rotation '{}', use 0, 90, 180 or 270",
+                value
+            )),
+        }
+    }
+}
+
+/// Mirroring of the image.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Flip {
+    /// Swap left and right.
+    Horizontal,
+
+    /// Swap top and bottom.
+    Vertical,
+}
+
+impl FromStr for Flip {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "h" => Ok(Flip::Horizontal),
+            "v" => Ok(Flip::Vertical),
+            _ => Err(format!("invalid flip '{}', use h or v", value)),
+        }
+    }
+}
+
+/// How the video is turned to appear upright on panels which are mounted rotated or mirrored.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Orientation {
+    /// Rotation of the video on the panel.
+    pub rotation: Rotation,
+
+    /// Mirroring of the video, before it gets rotated.
+    pub flip: Option<Flip>,
+}
+
+impl Orientation {
+    /// Size of the video before it got turned into the given size on the panel.
+    pub fn source_size(&self, width: u32, height: u32) -> (u32, u32) {
+        self.rotation.source_size(width, height)
+    }
+
+    /// Turn frame as it should appear on the panel.
+    pub fn apply(&self, frame: GrayFrame) -> GrayFrame {
+        if self.rotation == Rotation::None && self.flip.is_none() {
+            return frame;
+        }
+
+        let (source_width, source_height) = (frame.width as i64, frame.height as i64);
+        let (width, height) = match self.rotation {
+            Rotation::None | Rotation::Clockwise180 => (frame.width, frame.height),
+            Rotation::Clockwise90 | Rotation::Clockwise270 => (frame.height, frame.width),
+        };
+
+        let mut data = Vec::with_capacity((width * height) as usize);
+        for y in 0..height as i64 {
+            for x in 0..width as i64 {
+                let (source_x, source_y) =
+                    self.rotation
+                        .source_position(x, y, source_width, source_height);
+                let (source_x, source_y) = match self.flip {
+                    None => (source_x, source_y),
+                    Some(Flip::Horizontal) => (source_width - 1 - source_x, source_y),
+                    Some(Flip::Vertical) => (source_x, source_height - 1 - source_y),
+                };
+
+                data.push(frame.data[source_y as usize * frame.stride + source_x as usize]);
+            }
+        }
+
+        GrayFrame {
+            data,
+            width,
+            height,
+            stride: width as usize,
+            timestamp: frame.timestamp,
+            scene_cut: frame.scene_cut,
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+use it8951::MockDevice;
+
+/// File formats the content of the simulated panel can be written to.
+enum Output {
+    /// One PNG file per frame, numbered after the file name of the given path.
+    Png { path: PathBuf, index: usize },
+
+    /// Animated GIF with one image per frame.
+    Gif(gif::Encoder<BufWriter<File>>),
+
+    /// Uncompressed YUV4MPEG2 stream with one luminance plane per frame.
+    Y4m(BufWriter<File>),
+}
+
+/// Write what the simulated panel shows after every displayed frame into a file, to preview the
+/// result without a device.
+pub struct Recorder {
+    device: MockDevice,
+    output: Output,
+    width: u32,
+    height: u32,
+
+    /// Display time of every frame in 1/100s, the unit of GIF frame delays.
+    delay: u16,
+}
+
+impl Recorder {
+    /// Create output file for the panel of the simulated device. The format is chosen by the file
+    /// extension (png, gif or y4m), frames are played back with the given frame rate.
+    pub fn create(
+        path: &Path,
+        device: MockDevice,
+        width: u32,
+        height: u32,
+        frame_rate: (u32, u32),
+    ) -> Result<Self> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_ascii_lowercase());
+
+        let output = match extension.as_deref() {
+            Some("png") => Output::Png {
+                path: path.to_path_buf(),
+                index: 0,
+            },
+            Some("gif") => {
+                // Frames are stored as gray values, so the palette maps every index onto itself
+                let palette: Vec<u8> = (0..=255).flat_map(|value| [value, value, value]).collect();
+                let mut encoder = gif::Encoder::new(
+                    create_file(path)?,
+                    width.try_into().context("Panel is too wide for GIF")?,
+                    height.try_into().context("Panel is too high for GIF")?,
+                    &palette,
+                )?;
+                encoder.set_repeat(gif::Repeat::Infinite)?;
+                Output::Gif(encoder)
+            }
+            Some("y4m") => {
+                let mut writer = create_file(path)?;
+                writeln!(
+                    writer,
+                    "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 Cmono",
+                    width, height, frame_rate.0, frame_rate.1
+                )?;
+                Output::Y4m(writer)
+            }
+            _ => bail!(
+                "Unknown output format of '{}', use png, gif or y4m",
+                path.display()
+            ),
+        };
+
+        let delay = (100.0 * frame_rate.1 as f64 / frame_rate.0 as f64).round() as u16;
+
+        Ok(Self {
+            device,
+            output,
+            width,
+            height,
+            delay: delay.max(1),
+        })
+    }
+
+    /// Write current content of the panel as the next frame.
+    pub fn record(&mut self) -> Result<()> {
+        let panel = self.device.panel();
+
+        match &mut self.output {
+            Output::Png { path, index } => {
+                let frame_path = numbered_path(path, *index);
+                let mut encoder =
+                    png::Encoder::new(create_file(&frame_path)?, self.width, self.height);
+                encoder.set_color(png::ColorType::Grayscale);
+                encoder.set_depth(png::BitDepth::Eight);
+
+                let mut writer = encoder.write_header()?;
+                writer.write_image_data(&panel)?;
+                writer.finish()?;
+                *index += 1;
+            }
+            Output::Gif(encoder) => {
+                let mut frame = gif::Frame::from_indexed_pixels(
+                    self.width as u16,
+                    self.height as u16,
+                    panel,
+                    None,
+                );
+                frame.delay = self.delay;
+                encoder.write_frame(&frame)?;
+            }
+            Output::Y4m(writer) => {
+                writer.write_all(b"FRAME\n")?;
+                writer.write_all(&panel)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write remaining data of the output file.
+    pub fn finish(self) -> Result<()> {
+        match self.output {
+            Output::Png { .. } => {}
+            Output::Gif(encoder) => encoder.into_inner()?.flush()?,
+            Output::Y4m(mut writer) => writer.flush()?,
+        }
+
+        Ok(())
+    }
+}
+
+fn create_file(path: &Path) -> Result<BufWriter<File>> {
+    let file = File::create(path)
+        .with_context(|| format!("Failed creating output file '{}'", path.display()))?;
+    Ok(BufWriter::new(file))
+}
+
+/// Path of the nth file of a sequence, "frames.png" becomes "frames-000042.png".
+fn numbered_path(path: &Path, index: usize) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy())
+        .unwrap_or_default();
+
+    path.with_file_name(format!("{}-{:06}.{}", stem, index, extension))
+}
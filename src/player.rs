@@ -0,0 +1,692 @@
+//! Play dithered video frames on the panel, uploading the next frames while the panel is still
+//! refreshing the current one.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, TryRecvError};
+use std::time::Duration;
+
+use crate::api::{Area, Mode, API, BGVR_REG, ONE_BIT_WIDTH_ALIGNMENT, PITCH_REG, UP1SR_REG};
+use crate::clock::PlaybackClock;
+use crate::dirty::changed_areas;
+use crate::dither::{BitDepth, BitmapFormat, Frame};
+use crate::error::{Error, Result};
+use crate::ghost::GhostTracker;
+use crate::ring::ImageBufferRing;
+use crate::transport::Transport;
+
+/// Share of the video area above which changed frames get refreshed completely instead of
+/// updating every changed area on its own.
+const FULL_REFRESH_COVERAGE: f32 = 0.5;
+
+/// Time to wait for the next frame when none is uploaded, before handing control back to the
+/// caller.
+const IDLE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Dithered video frame together with its presentation time.
+pub struct VideoFrame {
+    /// Packed pixel data of the frame.
+    pub data: Frame,
+
+    /// Presentation time of the frame, relative to the beginning of the video.
+    pub timestamp: Duration,
+
+    /// Frame starts a new scene, the panel should be cleaned up when displaying it.
+    pub scene_cut: bool,
+}
+
+/// How the [`Player`] displays frames.
+#[derive(Clone, Copy, Debug)]
+pub struct PlayerOptions {
+    /// Width of every frame.
+    pub width: u32,
+
+    /// Height of every frame.
+    pub height: u32,
+
+    /// Number of bits used to represent one pixel.
+    pub depth: BitDepth,
+
+    /// Position of the top left corner of the frames on the panel, they are centered when there
+    /// is none.
+    pub position: Option<(u32, u32)>,
+
+    /// Format of 1bpp images in the controller memory.
+    pub bitmap: BitmapFormat,
+
+    /// Transitions per pixel in a region of the panel after which it gets cleaned up, see
+    /// [`GhostTracker`].
+    pub ghost_threshold: f32,
+
+    /// Maximum number of frames between two cleanups.
+    pub ghost_interval: usize,
+
+    /// Display frames in sync with their presentation time, dropping late ones.
+    pub realtime: bool,
+
+    /// Only upload and refresh the areas which changed since the previous frame.
+    pub partial: bool,
+}
+
+/// Result of one step of the [`Player`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Progress {
+    /// A frame got displayed.
+    Displayed,
+
+    /// No frame arrived in time, there might be more later on.
+    Waiting,
+
+    /// All frames got displayed and no more will arrive.
+    Finished,
+}
+
+/// Display frames on the panel one after another.
+///
+/// The image buffer of the controller is used as a ring of frames (see [`ImageBufferRing`]), so
+/// the next frames get uploaded while the panel is still busy. Fast display modes are used for
+/// most frames, the panel gets cleaned up with a slower mode when [`GhostTracker`] says so.
+pub struct Player<T: Transport> {
+    api: API<T>,
+    options: PlayerOptions,
+    area: Area,
+    fast_mode: Mode,
+    clean_mode: Mode,
+    ring: ImageBufferRing,
+    ghost: GhostTracker,
+    clock: PlaybackClock,
+
+    /// Frames which are uploaded but not displayed yet, together with their address.
+    uploaded: VecDeque<(u32, VideoFrame)>,
+
+    /// Frame which has been received but not uploaded yet.
+    next_frame: Option<VideoFrame>,
+
+    /// Frames which are stored in the slots of the image buffer (only tracked for partial
+    /// updates).
+    slot_contents: HashMap<u32, Frame>,
+
+    /// Frame which is shown on the panel.
+    displayed: Option<Frame>,
+
+    /// Sender of the frames is gone.
+    finished: bool,
+
+    dropped_frames: usize,
+}
+
+impl<T: Transport> Player<T> {
+    /// Display frames with the given options on the panel of the API. The panel gets configured
+    /// for their bit depth and the given VCOM value.
+    pub fn new(mut api: API<T>, options: PlayerOptions, vcom: f32) -> Result<Self> {
+        let PlayerOptions {
+            width,
+            height,
+            depth,
+            ..
+        } = options;
+
+        if width % width_alignment(depth) != 0 {
+            return Err(Error::InvalidGeometry(format!(
+                "width of {}bpp frames needs to be a multiple of {}, got {}",
+                depth.bits(),
+                width_alignment(depth),
+                width
+            )));
+        }
+
+        configure_panel(&mut api, depth, width, vcom, &options.bitmap)?;
+
+        // Place frames where it was requested, otherwise in the center of the panel
+        let area = match options.position {
+            Some((x, y)) => Area::new(x, y, width, height),
+            None => api.centered_area(),
+        };
+
+        // Fail right away instead of after the first frame got uploaded
+        api.check_area(area)?;
+
+        let (fast_mode, clean_mode) = display_modes(depth);
+        let ring = ImageBufferRing::new(api.get_system_info(), image_size(depth, width, height));
+        let ghost = GhostTracker::new(
+            width,
+            height,
+            depth,
+            options.ghost_threshold,
+            options.ghost_interval,
+        );
+
+        Ok(Self {
+            api,
+            options,
+            area,
+            fast_mode,
+            clean_mode,
+            ring,
+            ghost,
+            clock: PlaybackClock::new(),
+            uploaded: VecDeque::new(),
+            next_frame: None,
+            slot_contents: HashMap::new(),
+            displayed: None,
+            finished: false,
+            dropped_frames: 0,
+        })
+    }
+
+    /// Number of frames which fit into the image buffer.
+    pub fn slots(&self) -> u32 {
+        self.ring.len()
+    }
+
+    /// Number of frames which have been dropped as they were late.
+    pub fn dropped_frames(&self) -> usize {
+        self.dropped_frames
+    }
+
+    /// Upload the next frames received from the channel into free slots of the image buffer and
+    /// display the one which is due. Waits a moment for new frames when none is uploaded.
+    pub fn step(&mut self, frames: &Receiver<VideoFrame>) -> Result<Progress> {
+        self.upload(frames)?;
+
+        let (mut address, mut frame) = match self.uploaded.pop_front() {
+            Some(uploaded) => uploaded,
+            None if self.finished => return Ok(Progress::Finished),
+            None => {
+                match frames.recv_timeout(IDLE_TIMEOUT) {
+                    Ok(frame) => self.next_frame = Some(frame),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => self.finished = true,
+                }
+
+                return Ok(Progress::Waiting);
+            }
+        };
+
+        if self.options.realtime {
+            // Skip frames when we are already late for the next one
+            while let Some((_, next_frame)) = self.uploaded.front() {
+                if !self.clock.is_due(next_frame.timestamp) {
+                    break;
+                }
+
+                (address, frame) = self.uploaded.pop_front().expect("frame is queued");
+                self.dropped_frames += 1;
+            }
+
+            self.clock.wait_until(frame.timestamp);
+        }
+
+        self.display(address, frame)?;
+
+        Ok(Progress::Displayed)
+    }
+
+    /// Clear the panel by making it completely white.
+    pub fn clear_display(&mut self) -> Result<()> {
+        self.api.clear_display()
+    }
+
+    /// Upload next frames into free slots of the image buffer while the panel is still
+    /// refreshing. The slot of the currently displayed frame is never touched.
+    fn upload(&mut self, frames: &Receiver<VideoFrame>) -> Result<()> {
+        let PlayerOptions {
+            width,
+            height,
+            depth,
+            ..
+        } = self.options;
+
+        while self.uploaded.len() < (self.ring.len() as usize - 1).max(1) {
+            let frame = match self.next_frame.take() {
+                Some(frame) => frame,
+                None => match frames.try_recv() {
+                    Ok(frame) => frame,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        self.finished = true;
+                        break;
+                    }
+                },
+            };
+
+            // Do not bother uploading a late frame when a newer one is due as well
+            if self.options.realtime && self.clock.is_due(frame.timestamp) {
+                if let Ok(newer_frame) = frames.try_recv() {
+                    let skip = self.clock.is_due(newer_frame.timestamp);
+                    self.next_frame = Some(newer_frame);
+
+                    if skip {
+                        self.dropped_frames += 1;
+                        continue;
+                    }
+                }
+            }
+
+            let address = self.ring.next_address();
+            let data = memory_image(&frame.data, depth, &self.options.bitmap);
+            if self.options.partial {
+                // Only transfer rows which changed since the frame stored in this slot
+                let changes = match self.slot_contents.get(&address) {
+                    Some(previous) => {
+                        changed_areas(previous, &frame.data, width, height, depth, 1.0)
+                            .expect("areas can cover the whole frame")
+                    }
+                    None => vec![Area::new(0, 0, width, height)],
+                };
+                upload_rows(&mut self.api, address, &data, &changes, depth, width)?;
+                self.slot_contents.insert(address, frame.data.clone());
+            } else {
+                self.api.set_memory(address, &data)?;
+            }
+            self.uploaded.push_back((address, frame));
+        }
+
+        Ok(())
+    }
+
+    /// Display the frame stored at the given address.
+    fn display(&mut self, address: u32, frame: VideoFrame) -> Result<()> {
+        let PlayerOptions {
+            width,
+            height,
+            depth,
+            ..
+        } = self.options;
+
+        // Sometimes draw image properly (this is slower) to avoid too much ghosting
+        let clean = self
+            .ghost
+            .next_frame(self.displayed.as_deref(), &frame.data, frame.scene_cut);
+
+        // Find out which areas changed since the last frame, when only a few did
+        let changes = match &self.displayed {
+            Some(previous) if self.options.partial && !clean => changed_areas(
+                previous,
+                &frame.data,
+                width,
+                height,
+                depth,
+                FULL_REFRESH_COVERAGE,
+            ),
+            _ => None,
+        };
+
+        // ... so we can finally display the images!
+        match changes {
+            Some(changes) => {
+                for change in changes {
+                    let change_address = address + image_offset(depth, width, change.x, change.y);
+                    let change_area = Area::new(
+                        self.area.x + change.x,
+                        self.area.y + change.y,
+                        change.width,
+                        change.height,
+                    );
+                    self.api
+                        .display_area(change_address, change_area, self.fast_mode)?;
+                }
+            }
+            None if clean => self.api.display_area(address, self.area, self.clean_mode)?,
+            // ... and display the others with a faster mode
+            None => self.api.display_area(address, self.area, self.fast_mode)?,
+        }
+
+        self.displayed = Some(frame.data);
+
+        Ok(())
+    }
+}
+
+/// Frame widths need to be a multiple of this for the given bit depth. The panel reads rows of
+/// 1bpp images in 32bit words and the image pitch is set in 32bit words for all bit depths.
+pub fn width_alignment(depth: BitDepth) -> u32 {
+    match depth {
+        BitDepth::One => ONE_BIT_WIDTH_ALIGNMENT,
+        _ => 4,
+    }
+}
+
+/// Set the VCOM value and prepare the controller registers for images of the given bit depth and
+/// width, and 1bpp images of the given format.
+pub fn configure_panel<T: Transport>(
+    api: &mut API<T>,
+    depth: BitDepth,
+    width: u32,
+    vcom: f32,
+    bitmap: &BitmapFormat,
+) -> Result<()> {
+    // Set VCOM value
+    api.set_vcom(vcom)?;
+
+    // Remember register value for later
+    let reg = api.get_memory_register_value(UP1SR_REG)?;
+
+    if depth == BitDepth::One {
+        // Enable 1bit drawing and image pitch mode
+        // 0000 0000 0000 0110 0000 0000 0000 0000
+        // |         |     ^^  |         |
+        // 113B      113A      1139      1138
+        api.set_memory_register_value(UP1SR_REG, reg | 1 << 18 | 1 << 17)?;
+
+        // Set bitmap mode color definition (by default 0 - set black(0x00), 1 - set white(0xf0))
+        api.set_memory_register_value(BGVR_REG, bitmap.colors())?;
+
+        // Set image pitch width
+        api.set_memory_register_value(PITCH_REG, width / 8 / 4)?;
+    } else {
+        // Disable 1bit drawing but keep image pitch mode for 8bpp images
+        api.set_memory_register_value(UP1SR_REG, (reg & !(1 << 18)) | 1 << 17)?;
+
+        // Set image pitch width
+        api.set_memory_register_value(PITCH_REG, width / 4)?;
+    }
+
+    Ok(())
+}
+
+/// Upload the rows of an image in the controller memory format which are covered by the changed
+/// areas into a slot of the image buffer.
+fn upload_rows<T: Transport>(
+    api: &mut API<T>,
+    address: u32,
+    data: &[u8],
+    changes: &[Area],
+    depth: BitDepth,
+    width: u32,
+) -> Result<()> {
+    // Rows are stored one after another, so whole rows are transferred in one go
+    for change in changes {
+        let start = image_offset(depth, width, 0, change.y);
+        let end = image_offset(depth, width, 0, change.y + change.height);
+        api.set_memory(address + start, &data[start as usize..end as usize])?;
+    }
+
+    Ok(())
+}
+
+/// Convert frame into the image stored in the controller memory: 1bpp frames in the given bitmap
+/// format, frames with gray levels as 8bpp images.
+pub fn memory_image<'a>(frame: &'a [u8], depth: BitDepth, bitmap: &BitmapFormat) -> Cow<'a, [u8]> {
+    match depth {
+        BitDepth::One => bitmap.encode(frame),
+        _ => Cow::Owned(depth.unpack(frame)),
+    }
+}
+
+/// Position of a pixel in an image in the controller memory, relative to the start of the image.
+fn image_offset(depth: BitDepth, width: u32, x: u32, y: u32) -> u32 {
+    image_size(depth, width, y) + image_size(depth, x, 1)
+}
+
+/// Byte size of an image in the controller memory. Frames with gray levels get stored as 8bpp
+/// images as the controller can not read other formats.
+pub fn image_size(depth: BitDepth, width: u32, height: u32) -> u32 {
+    match depth {
+        BitDepth::One => (width * height) / 8,
+        _ => width * height,
+    }
+}
+
+/// Display modes for frames of the given bit depth: A fast one used for most frames and a slower
+/// one to paint the image properly from time to time.
+pub fn display_modes(depth: BitDepth) -> (Mode, Mode) {
+    match depth {
+        // Fast, non-flashy black and white mode
+        BitDepth::One => (Mode::A2, Mode::GL16),
+        // Fast mode supporting 4 gray levels
+        BitDepth::Two => (Mode::DU4, Mode::GL16),
+        // All 16 gray levels
+        BitDepth::Four => (Mode::GL16, Mode::GC16),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dither::BitOrder;
+    use crate::mock::MockDevice;
+    use std::sync::mpsc;
+
+    const PANEL_WIDTH: u32 = 128;
+    const PANEL_HEIGHT: u32 = 64;
+    const WIDTH: u32 = 64;
+    const HEIGHT: u32 = 32;
+
+    fn options(depth: BitDepth) -> PlayerOptions {
+        PlayerOptions {
+            width: WIDTH,
+            height: HEIGHT,
+            depth,
+            position: None,
+            bitmap: BitmapFormat::default(),
+            ghost_threshold: 3.0,
+            ghost_interval: 32,
+            realtime: false,
+            partial: false,
+        }
+    }
+
+    fn frame(data: Frame, timestamp_ms: u64) -> VideoFrame {
+        VideoFrame {
+            data,
+            timestamp: Duration::from_millis(timestamp_ms),
+            scene_cut: false,
+        }
+    }
+
+    /// Play all frames on a simulated panel until the player is done.
+    fn play(
+        device: &MockDevice,
+        options: PlayerOptions,
+        frames: Vec<VideoFrame>,
+    ) -> Player<MockDevice> {
+        let api = API::new(device.clone(), WIDTH, HEIGHT).unwrap();
+        let mut player = Player::new(api, options, -1.58).unwrap();
+
+        let (frame_tx, frame_rx) = mpsc::sync_channel(frames.len());
+        for frame in frames {
+            frame_tx.send(frame).unwrap();
+        }
+        drop(frame_tx);
+
+        while player.step(&frame_rx).unwrap() != Progress::Finished {}
+        player
+    }
+
+    /// Gray values of the centered area of the panel.
+    fn shown(device: &MockDevice) -> Vec<u8> {
+        let (left, top) = ((PANEL_WIDTH - WIDTH) / 2, (PANEL_HEIGHT - HEIGHT) / 2);
+        let panel = device.panel();
+        (top..top + HEIGHT)
+            .flat_map(|y| {
+                let start = (y * PANEL_WIDTH + left) as usize;
+                panel[start..start + WIDTH as usize].to_vec()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn plays_all_frames() {
+        let device = MockDevice::new(PANEL_WIDTH, PANEL_HEIGHT);
+        let size = BitDepth::One.frame_size(WIDTH, HEIGHT);
+        let frames = vec![
+            frame(vec![0x00; size], 0),
+            frame(vec![0xff; size], 40),
+            frame(vec![0x0f; size], 80),
+        ];
+        play(&device, options(BitDepth::One), frames);
+
+        // First frame cleans the panel up, the others are displayed in the fast mode
+        let modes: Vec<Mode> = device
+            .display_updates()
+            .iter()
+            .map(|update| update.mode)
+            .collect();
+        assert_eq!(modes, vec![Mode::GL16, Mode::A2, Mode::A2]);
+        assert_eq!(device.vcom(), Some(1580));
+
+        // Set bits are white, starting with the lowest one
+        for (index, value) in shown(&device).into_iter().enumerate() {
+            let expected = if index % 8 < 4 { 0xf0 } else { 0x00 };
+            assert_eq!(value, expected, "pixel {}", index);
+        }
+    }
+
+    #[test]
+    fn plays_gray_frames() {
+        let device = MockDevice::new(PANEL_WIDTH, PANEL_HEIGHT);
+        let size = BitDepth::Four.frame_size(WIDTH, HEIGHT);
+        let levels: Frame = (0..size).map(|index| index as u8).collect();
+        play(
+            &device,
+            options(BitDepth::Four),
+            vec![frame(levels.clone(), 0)],
+        );
+
+        assert_eq!(shown(&device), BitDepth::Four.unpack(&levels));
+        assert_eq!(device.display_updates()[0].mode, Mode::GC16);
+    }
+
+    #[test]
+    fn shows_same_picture_in_every_bitmap_format() {
+        let size = BitDepth::One.frame_size(WIDTH, HEIGHT);
+        let data: Frame = (0..size).map(|index| (index * 37) as u8).collect();
+
+        // Set bits are white, starting with the lowest one
+        let expected: Vec<u8> = (0..(WIDTH * HEIGHT) as usize)
+            .map(|index| {
+                if data[index / 8] & 1 << (index % 8) != 0 {
+                    0xf0
+                } else {
+                    0x00
+                }
+            })
+            .collect();
+
+        for (bit_order, invert, colors) in [
+            (BitOrder::LsbFirst, false, 0x00f0),
+            (BitOrder::LsbFirst, true, 0xf000),
+            (BitOrder::MsbFirst, false, 0x00f0),
+            (BitOrder::MsbFirst, true, 0xf000),
+        ] {
+            let device = MockDevice::new(PANEL_WIDTH, PANEL_HEIGHT).with_bit_order(bit_order);
+            let options = PlayerOptions {
+                bitmap: BitmapFormat {
+                    bit_order,
+                    invert,
+                    ..BitmapFormat::default()
+                },
+                ..options(BitDepth::One)
+            };
+            // Registers get restored as soon as the player is gone
+            let _player = play(&device, options, vec![frame(data.clone(), 0)]);
+
+            assert_eq!(
+                device.register(BGVR_REG),
+                colors,
+                "{:?} {}",
+                bit_order,
+                invert
+            );
+            assert_eq!(shown(&device), expected, "{:?} {}", bit_order, invert);
+        }
+    }
+
+    #[test]
+    fn partial_updates_show_same_picture() {
+        for depth in [BitDepth::One, BitDepth::Four] {
+            let size = depth.frame_size(WIDTH, HEIGHT);
+            let row_bytes = depth.frame_size(WIDTH, 1);
+
+            // Few rows change between the frames, on the left and on the right side
+            let mut frames = vec![vec![0x00; size]];
+            for (row, byte) in [(3, 1), (20, row_bytes - 1), (21, 0)] {
+                let mut data = frames.last().unwrap().clone();
+                data[row * row_bytes + byte] = 0x5a;
+                frames.push(data);
+            }
+            let frames = || -> Vec<VideoFrame> {
+                frames.iter().map(|data| frame(data.clone(), 0)).collect()
+            };
+
+            let full = MockDevice::new(PANEL_WIDTH, PANEL_HEIGHT);
+            play(&full, options(depth), frames());
+
+            let partial = MockDevice::new(PANEL_WIDTH, PANEL_HEIGHT);
+            let options = PlayerOptions {
+                partial: true,
+                ..options(depth)
+            };
+            play(&partial, options, frames());
+
+            assert_eq!(partial.panel(), full.panel(), "{:?}", depth);
+
+            // Only the first frame got displayed completely
+            let updates = partial.display_updates();
+            assert_eq!(updates.len(), 4);
+            assert_eq!(updates[0].width, WIDTH);
+            assert!(updates[1..]
+                .iter()
+                .all(|update| update.width == 32 && update.height == 1));
+        }
+    }
+
+    #[test]
+    fn rejects_unaligned_position_before_playing() {
+        let device = MockDevice::new(PANEL_WIDTH, PANEL_HEIGHT);
+        let api = API::new(device.clone(), WIDTH, HEIGHT).unwrap();
+        let options = PlayerOptions {
+            position: Some((4, 0)),
+            ..options(BitDepth::One)
+        };
+
+        assert!(matches!(
+            Player::new(api, options, -1.58),
+            Err(Error::InvalidGeometry(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_unaligned_width() {
+        for (depth, width) in [(BitDepth::One, 48), (BitDepth::Four, 62)] {
+            let device = MockDevice::new(PANEL_WIDTH, PANEL_HEIGHT);
+            let api = API::new(device, width, HEIGHT).unwrap();
+            let options = PlayerOptions {
+                width,
+                ..options(depth)
+            };
+
+            assert!(matches!(
+                Player::new(api, options, -1.58),
+                Err(Error::InvalidGeometry(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn drops_late_frames_in_realtime() {
+        let device = MockDevice::new(PANEL_WIDTH, PANEL_HEIGHT);
+        let size = BitDepth::One.frame_size(WIDTH, HEIGHT);
+        let options = PlayerOptions {
+            realtime: true,
+            ..options(BitDepth::One)
+        };
+
+        // Second frame is already late when the first one is displayed, as the third one is due
+        // as well
+        let frames = vec![
+            frame(vec![0x00; size], 0),
+            frame(vec![0xff; size], 0),
+            frame(vec![0x0f; size], 0),
+            frame(vec![0xf0; size], 200),
+        ];
+        let player = play(&device, options, frames);
+
+        assert_eq!(player.dropped_frames(), 1);
+        assert_eq!(device.display_updates().len(), 3);
+        assert!(shown(&device)[..8]
+            .iter()
+            .eq(&[0x00, 0x00, 0x00, 0x00, 0xf0, 0xf0, 0xf0, 0xf0]));
+    }
+}
@@ -0,0 +1,53 @@
+//! Store multiple frames in the image buffer of the controller at once.
+
+use crate::api::SystemInfo;
+
+/// Alignment of every slot address in the image buffer.
+const SLOT_ALIGNMENT: u32 = 4;
+
+/// Divides the image buffer of the controller into slots which can each hold one frame.
+///
+/// The image buffer has space for (at least) one 8bpp image of the size of the panel, so multiple
+/// smaller 1bpp frames fit into it. This allows uploading the next frames while the panel is
+/// still busy refreshing the current one.
+pub struct ImageBufferRing {
+    base: u32,
+    slot_size: u32,
+    slots: u32,
+    next_slot: u32,
+}
+
+impl ImageBufferRing {
+    /// Divide the image buffer described by the system information into slots of the given frame
+    /// size (in bytes).
+    pub fn new(system_info: &SystemInfo, frame_size: u32) -> Self {
+        let buffer_size = system_info.width * system_info.height * system_info.num_img_buf.max(1);
+        let slot_size = frame_size.div_ceil(SLOT_ALIGNMENT) * SLOT_ALIGNMENT;
+
+        Self {
+            base: system_info.image_buffer_base,
+            slot_size,
+            slots: (buffer_size / slot_size).max(1),
+            next_slot: 0,
+        }
+    }
+
+    /// Number of frames which fit into the image buffer.
+    pub fn len(&self) -> u32 {
+        self.slots
+    }
+
+    /// Returns true if the image buffer has no slots, which never happens as there is always at
+    /// least one.
+    pub fn is_empty(&self) -> bool {
+        self.slots == 0
+    }
+
+    /// Return the address of the next slot, starting from the beginning when the end of the
+    /// buffer has been reached.
+    pub fn next_address(&mut self) -> u32 {
+        let address = self.base + self.next_slot * self.slot_size;
+        self.next_slot = (self.next_slot + 1) % self.slots;
+        address
+    }
+}
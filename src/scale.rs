@@ -0,0 +1,260 @@
+use std::str::FromStr;
+
+use ffmpeg_next::software::scaling::flag::Flags;
+
+/// How the video is fitted into the target dimensions when their aspect ratios differ.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ScaleMode {
+    /// Show the whole video, filling the remaining space with the background color (letterbox).
+    Fit,
+
+    /// Fill the whole target, cropping the parts of the video which do not fit.
+    Fill,
+
+    /// Stretch the video to the target dimensions, distorting it.
+    Stretch,
+}
+
+impl ScaleMode {
+    /// Names of all modes, as they are accepted on the command line.
+    pub const VARIANTS: [&'static str; 3] = ["fit", "fill", "stretch"];
+}
+
+impl FromStr for ScaleMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "fit" => Ok(ScaleMode::Fit),
+            "fill" => Ok(ScaleMode::Fill),
+            "stretch" => Ok(ScaleMode::Stretch),
+            _ => Err(format!("unknown scale mode '{}'", value)),
+        }
+    }
+}
+
+/// Algorithm used to scale the video.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ScaleAlgorithm {
+    /// Fast, but blurs fine details when shrinking.
+    Bilinear,
+
+    /// Sharper than bilinear.
+    Bicubic,
+
+    /// Sharpest, but slowest.
+    Lanczos,
+
+    /// Averages all covered pixels, good for shrinking a lot.
+    Area,
+}
+
+impl ScaleAlgorithm {
+    /// Names of all algorithms, as they are accepted on the command line.
+    pub const VARIANTS: [&'static str; 4] = ["bilinear", "bicubic", "lanczos", "area"];
+
+    /// Scaler flags selecting this algorithm.
+    pub fn flags(&self) -> Flags {
+        match self {
+            ScaleAlgorithm::Bilinear => Flags::BILINEAR,
+            ScaleAlgorithm::Bicubic => Flags::BICUBIC,
+            ScaleAlgorithm::Lanczos => Flags::LANCZOS,
+            ScaleAlgorithm::Area => Flags::AREA,
+        }
+    }
+}
+
+impl FromStr for ScaleAlgorithm {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "bilinear" => Ok(ScaleAlgorithm::Bilinear),
+            "bicubic" => Ok(ScaleAlgorithm::Bicubic),
+            "lanczos" => Ok(ScaleAlgorithm::Lanczos),
+            "area" => Ok(ScaleAlgorithm::Area),
+            _ => Err(format!("unknown scale algorithm '{}'", value)),
+        }
+    }
+}
+
+/// Rectangle in pixels.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Rect {
+    /// Left position of the rectangle.
+    pub x: u32,
+
+    /// Top position of the rectangle.
+    pub y: u32,
+
+    /// Width of the rectangle.
+    pub width: u32,
+
+    /// Height of the rectangle.
+    pub height: u32,
+}
+
+impl Rect {
+    /// Rectangle at the given position with the given size.
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Part of this rectangle which lies within the other one, if there is any. Rectangles
+    /// reaching beyond the largest coordinate end there.
+    fn intersect(&self, other: &Rect) -> Option<Rect> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = self
+            .x
+            .saturating_add(self.width)
+            .min(other.x.saturating_add(other.width));
+        let bottom = self
+            .y
+            .saturating_add(self.height)
+            .min(other.y.saturating_add(other.height));
+
+        if right <= x || bottom <= y {
+            return None;
+        }
+
+        Some(Rect::new(x, y, right - x, bottom - y))
+    }
+}
+
+/// Parsed from "x,y,width,height".
+impl FromStr for Rect {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid rectangle '{}', use x,y,width,height", value);
+
+        let numbers = value
+            .split(',')
+            .map(|number| number.trim().parse())
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|_| invalid())?;
+
+        match numbers[..] {
+            [x, y, width, height] if width > 0 && height > 0 => Ok(Rect::new(x, y, width, height)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Settings how decoded frames get scaled to the target dimensions.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Scaling {
+    /// How the video is fitted into the target dimensions.
+    pub mode: ScaleMode,
+
+    /// Algorithm used to scale the video.
+    pub algorithm: ScaleAlgorithm,
+
+    /// Part of the video which is shown, in pixels of the decoded frames. The whole frame is shown
+    /// when not given.
+    pub crop: Option<Rect>,
+
+    /// Gray value of the area around the video which is not covered in fit mode.
+    pub background: u8,
+}
+
+/// Part of the decoded frame which gets scaled and where it ends up in the target frame.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Placement {
+    /// Part of the decoded frame, in its pixels.
+    pub source: Rect,
+
+    /// Part of the target frame the source gets scaled to.
+    pub target: Rect,
+}
+
+impl Placement {
+    /// Calculate placement of the decoded frame in the target frame. The sample aspect ratio is
+    /// the width of a decoded pixel relative to its height.
+    ///
+    /// Returns `None` when the crop rectangle lies outside of the decoded frame.
+    pub fn new(
+        source_width: u32,
+        source_height: u32,
+        sample_aspect_ratio: f64,
+        target_width: u32,
+        target_height: u32,
+        scaling: &Scaling,
+    ) -> Option<Self> {
+        let frame = Rect::new(0, 0, source_width, source_height);
+        let mut source = match &scaling.crop {
+            Some(crop) => crop.intersect(&frame)?,
+            None => frame,
+        };
+        let mut target = Rect::new(0, 0, target_width, target_height);
+
+        // Aspect ratios as they appear on screen
+        let source_aspect = source.width as f64 * sample_aspect_ratio / source.height as f64;
+        let target_aspect = target_width as f64 / target_height as f64;
+
+        match scaling.mode {
+            ScaleMode::Fit => {
+                // Shrink target along the side where the video is relatively shorter
+                if source_aspect > target_aspect {
+                    let height = (target_width as f64 / source_aspect).round() as u32;
+                    target.height = height.clamp(1, target_height);
+                    target.y = (target_height - target.height) / 2;
+                } else {
+                    let width = (target_height as f64 * source_aspect).round() as u32;
+                    target.width = width.clamp(1, target_width);
+                    target.x = (target_width - target.width) / 2;
+                }
+            }
+            ScaleMode::Fill => {
+                // Cut off the sides of the video where it is relatively longer
+                if source_aspect > target_aspect {
+                    let width =
+                        (source.height as f64 * target_aspect / sample_aspect_ratio).round() as u32;
+                    let width = width.clamp(1, source.width);
+                    source.x += (source.width - width) / 2;
+                    source.width = width;
+                } else {
+                    let height =
+                        (source.width as f64 * sample_aspect_ratio / target_aspect).round() as u32;
+                    let height = height.clamp(1, source.height);
+                    source.y += (source.height - height) / 2;
+                    source.height = height;
+                }
+            }
+            ScaleMode::Stretch => {}
+        }
+
+        Some(Self { source, target })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intersects_rectangles() {
+        let frame = Rect::new(0, 0, 1920, 1080);
+        assert_eq!(
+            Rect::new(100, 50, 1920, 100).intersect(&frame),
+            Some(Rect::new(100, 50, 1820, 100))
+        );
+        assert_eq!(Rect::new(1920, 0, 10, 10).intersect(&frame), None);
+    }
+
+    #[test]
+    fn intersects_rectangles_at_end_of_coordinates() {
+        let frame = Rect::new(0, 0, 1920, 1080);
+        let crop: Rect = "100,4294967295,4294967295,1".parse().unwrap();
+        assert_eq!(crop.intersect(&frame), None);
+
+        let crop: Rect = "100,50,4294967295,4294967295".parse().unwrap();
+        assert_eq!(crop.intersect(&frame), Some(Rect::new(100, 50, 1820, 1030)));
+    }
+}
@@ -0,0 +1,136 @@
+use crate::video::GrayFrame;
+
+/// Share of the darkest and brightest pixels which are ignored when finding the levels of a
+/// frame, so a few outliers do not prevent stretching its contrast.
+const AUTO_LEVELS_CLIP: f32 = 0.005;
+
+/// Settings how the gray values of the video get adjusted before dithering.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Tone {
+    /// Value added to every pixel, from -1.0 (black) to 1.0 (white).
+    pub brightness: f32,
+
+    /// Factor the differences to medium gray get multiplied with.
+    pub contrast: f32,
+
+    /// Gamma correction, values above 1.0 brighten up dark parts of the video.
+    pub gamma: f32,
+
+    /// Gray values which become black and white, everything in between gets stretched.
+    pub levels: Option<(u8, u8)>,
+
+    /// Find black and white point of every frame by itself, instead of using fixed levels.
+    pub auto_levels: bool,
+
+    /// Share (0.0 to 1.0) of the levels of the previous frames kept in every frame, to avoid
+    /// sudden jumps in brightness. Levels are reset on every scene cut.
+    pub smoothing: f32,
+}
+
+impl Tone {
+    /// Returns true if gray values are left as they are.
+    fn is_neutral(&self) -> bool {
+        self.brightness == 0.0
+            && self.contrast == 1.0
+            && self.gamma == 1.0
+            && self.levels.is_none_or(|levels| levels == (0, 255))
+            && !self.auto_levels
+    }
+}
+
+/// Adjust gray values of frames by the tone settings.
+pub struct ToneMapper {
+    tone: Tone,
+
+    /// Black and white point found in the previous frames.
+    auto_levels: Option<(f32, f32)>,
+}
+
+impl ToneMapper {
+    /// Adjust frames with the given settings.
+    pub fn new(tone: Tone) -> Self {
+        Self {
+            tone,
+            auto_levels: None,
+        }
+    }
+
+    /// Adjust gray values of the frame in place.
+    pub fn apply(&mut self, frame: &mut GrayFrame) {
+        if self.tone.is_neutral() {
+            return;
+        }
+
+        let (black, white) = if self.tone.auto_levels {
+            let (black, white) = levels(frame);
+
+            let levels = match self.auto_levels {
+                Some((previous_black, previous_white)) if !frame.scene_cut => {
+                    let smoothing = self.tone.smoothing;
+                    (
+                        previous_black * smoothing + black * (1.0 - smoothing),
+                        previous_white * smoothing + white * (1.0 - smoothing),
+                    )
+                }
+                _ => (black, white),
+            };
+
+            self.auto_levels = Some(levels);
+            levels
+        } else {
+            let (black, white) = self.tone.levels.unwrap_or((0, 255));
+            (black as f32, white as f32)
+        };
+
+        let table = self.lookup_table(black, white);
+        for value in frame.data.iter_mut() {
+            *value = table[*value as usize];
+        }
+    }
+
+    /// Map every gray value to its adjusted one, with the given black and white point.
+    fn lookup_table(&self, black: f32, white: f32) -> [u8; 256] {
+        let range = (white - black).max(1.0);
+        let mut table = [0; 256];
+
+        for (value, entry) in table.iter_mut().enumerate() {
+            let mut level = (value as f32 - black) / range;
+            level = (level - 0.5) * self.tone.contrast + 0.5;
+            level += self.tone.brightness;
+            level = level.clamp(0.0, 1.0).powf(1.0 / self.tone.gamma);
+
+            *entry = (level * 255.0).round() as u8;
+        }
+
+        table
+    }
+}
+
+/// Darkest and brightest gray values of the frame, ignoring a few outliers.
+fn levels(frame: &GrayFrame) -> (f32, f32) {
+    let mut histogram = [0u32; 256];
+    for row in frame.data.chunks(frame.stride).take(frame.height as usize) {
+        for &value in &row[..frame.width as usize] {
+            histogram[value as usize] += 1;
+        }
+    }
+
+    let clip = ((frame.width * frame.height) as f32 * AUTO_LEVELS_CLIP) as u32;
+    let black = clipped_level(&histogram, clip, 0..256);
+    let white = clipped_level(&histogram, clip, (0..256).rev());
+
+    (black, white.max(black))
+}
+
+/// First gray value in the given order after more than the clipped number of pixels.
+fn clipped_level(histogram: &[u32; 256], clip: u32, values: impl Iterator<Item = usize>) -> f32 {
+    let mut count = 0;
+    for value in values {
+        count += histogram[value];
+        if count > clip {
+            return value as f32;
+        }
+    }
+
+    0.0
+}
@@ -0,0 +1,60 @@
+//! Abstraction over the connection commands are sent through.
+
+use std::mem;
+
+use bincode::config::Options;
+use serde::Serialize;
+
+use crate::error::Result;
+
+/// Send SCSI commands to an IT8951 controller.
+///
+/// Implementors only need to take care of moving the raw bytes, (de-)serializing the values is
+/// handled by the provided methods.
+pub trait Transport {
+    /// Send a command and read `length` bytes of data from the device.
+    fn read_command_raw(&mut self, command: &[u8; 16], length: usize) -> Result<Vec<u8>>;
+
+    /// Send a command together with the given data to the device.
+    fn write_command_raw(&mut self, command: &[u8; 16], data: &[u8]) -> Result<()>;
+
+    /// Send a command and deserialize the returned data into the required result type.
+    fn read_command<T: serde::de::DeserializeOwned, O: bincode::config::Options>(
+        &mut self,
+        command: &[u8; 16],
+        bincode_options: O,
+    ) -> Result<T>
+    where
+        Self: Sized,
+    {
+        let length = mem::size_of::<T>();
+        let buf = self.read_command_raw(command, length)?;
+
+        // Transform data into required result type
+        let result: T = bincode_options.with_fixint_encoding().deserialize(&buf)?;
+
+        Ok(result)
+    }
+
+    /// Send a command together with a serialized value and any additional data.
+    fn write_command<T: Serialize, O: bincode::config::Options>(
+        &mut self,
+        command: &[u8; 16],
+        value: T,
+        data: &[u8],
+        bincode_options: O,
+    ) -> Result<()>
+    where
+        Self: Sized,
+    {
+        // Transform the value into data
+        let mut value_data: Vec<u8> = bincode_options.with_fixint_encoding().serialize(&value)?;
+
+        // Combine this with any additional data
+        let mut bulk_data: Vec<u8> = Vec::new();
+        bulk_data.append(&mut value_data);
+        bulk_data.extend_from_slice(data);
+
+        self.write_command_raw(command, &bulk_data)
+    }
+}
@@ -1,11 +1,45 @@
-use std::mem;
+//! Send SCSI commands to the IT8951 controller via USB (Bulk-Only Transport).
+
+use std::fmt;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::time::Duration;
 
 use bincode::config::Options;
-use rusb::{DeviceHandle, Error, GlobalContext, Result};
+use rusb::{Device, DeviceHandle, GlobalContext};
 use serde::{Deserialize, Serialize};
 
+use crate::api::SystemInfo;
+use crate::error::{Error, Result};
+use crate::transport::Transport;
+
+/// USB vendor and product ID of IT8951.
+const VENDOR_ID: u16 = 0x048d;
+const PRODUCT_ID: u16 = 0x8951;
+
+/// SCSI via USB parameter.
+const INTERFACE: u8 = 0;
+const ENDPOINT_IN: u8 = 0x81;
+const ENDPOINT_OUT: u8 = 0x02;
+const SCSI_TIMEOUT_MS: u64 = 1000;
+
+/// Signature of every Command Block Wrapper ("USBC").
+const CBW_SIGNATURE: [u8; 4] = [0x55, 0x53, 0x42, 0x43];
+
+/// Signature of every Command Status Wrapper ("USBS").
+const CSW_SIGNATURE: [u8; 4] = [0x55, 0x53, 0x42, 0x53];
+
+/// Byte size of every Command Status Wrapper.
+const CSW_LENGTH: usize = 13;
+
+/// Command Status Wrapper status values.
+const CSW_STATUS_PASSED: u8 = 0x00;
+const CSW_STATUS_FAILED: u8 = 0x01;
+
+/// Class-specific request to reset a Bulk-Only Mass Storage device.
+const BULK_ONLY_RESET_REQUEST_TYPE: u8 = 0x21;
+const BULK_ONLY_RESET_REQUEST: u8 = 0xff;
+
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 enum Direction {
     IN,
@@ -35,134 +69,348 @@ struct CommandStatusWrapper {
 
 static TAG: AtomicU32 = AtomicU32::new(1);
 
-// Send SCSI commands over USB.
+/// Send SCSI commands over USB.
 pub struct ScsiOverUsbConnection {
+    /// Handle of the opened USB device.
     pub device_handle: DeviceHandle<GlobalContext>,
+
+    /// Number of the claimed interface.
+    pub interface_number: u8,
+
+    /// Endpoint to send commands and data to.
     pub endpoint_out: u8,
+
+    /// Endpoint to receive data and status from.
     pub endpoint_in: u8,
+
+    /// Timeout of every bulk transfer.
     pub timeout: Duration,
 }
 
-impl ScsiOverUsbConnection {
-    pub fn read_command<T: serde::de::DeserializeOwned, O: bincode::config::Options>(
-        &mut self,
-        command: &[u8; 16],
-        bincode_options: O,
-    ) -> Result<T> {
-        let length = mem::size_of::<T>();
+/// IT8951 controller connected via USB.
+#[derive(Clone, Debug)]
+pub struct UsbDevice {
+    /// Number of the bus the device is connected to.
+    pub bus: u8,
 
-        // Issue CBW block
-        let cbw_data = &get_command_block_wrapper(command, length as u32, Direction::IN);
-        self.device_handle
-            .write_bulk(self.endpoint_out, cbw_data, self.timeout)?;
+    /// Address of the device on its bus.
+    pub address: u8,
 
-        // Now read the data
-        let mut buf: Vec<u8> = vec![0; length];
-        self.device_handle
-            .read_bulk(self.endpoint_in, &mut buf, self.timeout)?;
+    /// Serial number of the device, if it reports one.
+    pub serial: Option<String>,
 
-        // Issue CBS block
-        self.send_status_block_wrapper()?;
+    device: Device<GlobalContext>,
+}
 
-        // Transform data into required result type
-        let result: T = bincode_options
-            .with_fixint_encoding()
-            .deserialize(&buf)
-            .unwrap();
+impl UsbDevice {
+    /// Find all IT8951 controllers connected via USB.
+    pub fn list() -> Result<Vec<UsbDevice>> {
+        let mut devices = Vec::new();
+
+        for device in rusb::devices()?.iter() {
+            let descriptor = device.device_descriptor()?;
+            if descriptor.vendor_id() != VENDOR_ID || descriptor.product_id() != PRODUCT_ID {
+                continue;
+            }
+
+            // Reading the serial number requires opening the device, which might not be allowed
+            let serial = device
+                .open()
+                .and_then(|handle| handle.read_serial_number_string_ascii(&descriptor))
+                .ok();
 
-        Ok(result)
+            devices.push(UsbDevice {
+                bus: device.bus_number(),
+                address: device.address(),
+                serial,
+                device,
+            });
+        }
+
+        Ok(devices)
     }
 
-    pub fn write_command<T: Serialize, O: bincode::config::Options>(
-        &mut self,
-        command: &[u8; 16],
-        value: T,
-        data: &[u8],
-        bincode_options: O,
-    ) -> Result<()> {
-        // Transform the value into data
-        let mut value_data: Vec<u8> = bincode_options
-            .with_fixint_encoding()
-            .serialize(&value)
-            .unwrap();
+    /// Find the first IT8951 controller matching the selector.
+    pub fn find(selector: &DeviceSelector) -> Result<UsbDevice> {
+        Self::list()?
+            .into_iter()
+            .find(|device| device.matches(selector))
+            .ok_or(Error::DeviceNotFound)
+    }
 
-        // Combine this with any additional data
-        let mut bulk_data: Vec<u8> = Vec::new();
-        bulk_data.append(&mut value_data);
-        bulk_data.extend_from_slice(data);
+    /// Returns true if the device is matching the selector.
+    pub fn matches(&self, selector: &DeviceSelector) -> bool {
+        match selector {
+            DeviceSelector::Any => true,
+            DeviceSelector::BusAddress { bus, address } => {
+                self.bus == *bus && self.address == *address
+            }
+            DeviceSelector::Serial(serial) => self.serial.as_ref() == Some(serial),
+        }
+    }
 
+    /// Open a connection to the device and ask it about its system configuration.
+    pub fn read_system_info(&self) -> Result<SystemInfo> {
+        let mut connection = ScsiOverUsbConnection::open(self)?;
+        SystemInfo::read(&mut connection)
+    }
+}
+
+impl fmt::Display for UsbDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:03}:{:03}", self.bus, self.address)?;
+        if let Some(serial) = &self.serial {
+            write!(f, " (serial {})", serial)?;
+        }
+        Ok(())
+    }
+}
+
+/// Select one of multiple connected IT8951 controllers.
+///
+/// Parsed from "bus:address" (for example "1:4" or "001:004") or a serial number. Values
+/// containing a colon are always taken as "bus:address".
+#[derive(Clone, PartialEq, Debug)]
+pub enum DeviceSelector {
+    /// Whichever device enumerates first.
+    Any,
+
+    /// Device at the given position on the USB bus.
+    BusAddress {
+        /// Number of the bus the device is connected to.
+        bus: u8,
+
+        /// Address of the device on its bus.
+        address: u8,
+    },
+
+    /// Device reporting the given serial number.
+    Serial(String),
+}
+
+impl FromStr for DeviceSelector {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        if let Some((bus, address)) = value.split_once(':') {
+            return match (bus.parse(), address.parse()) {
+                (Ok(bus), Ok(address)) => Ok(DeviceSelector::BusAddress { bus, address }),
+                _ => Err(format!(
+                    "invalid device '{}', use bus:address with numbers up to 255",
+                    value
+                )),
+            };
+        }
+
+        if value.is_empty() {
+            return Err("device can not be empty".to_string());
+        }
+
+        Ok(DeviceSelector::Serial(value.to_string()))
+    }
+}
+
+impl Transport for ScsiOverUsbConnection {
+    fn read_command_raw(&mut self, command: &[u8; 16], length: usize) -> Result<Vec<u8>> {
         // Issue CBW block
-        let cbw_data = &get_command_block_wrapper(command, bulk_data.len() as u32, Direction::OUT);
+        let tag = next_tag();
+        let cbw_data = &get_command_block_wrapper(command, tag, length as u32, Direction::IN)?;
         self.device_handle
             .write_bulk(self.endpoint_out, cbw_data, self.timeout)?;
 
-        // Now write the data for the value
-        self.device_handle
-            .write_bulk(self.endpoint_out, &bulk_data, self.timeout)?;
+        // Now read the data
+        let mut buf: Vec<u8> = vec![0; length];
+        let result = self
+            .device_handle
+            .read_bulk(self.endpoint_in, &mut buf, self.timeout);
+        let read = self.data_transferred(result, self.endpoint_in)?;
 
         // Issue CBS block
-        self.send_status_block_wrapper()?;
+        self.send_status_block_wrapper(tag)?;
 
-        Ok(())
+        if read != length {
+            return Err(Error::DataResidue((length - read) as u32));
+        }
+
+        Ok(buf)
     }
 
-    pub fn write_command_raw(&mut self, command: &[u8; 16], data: &[u8]) -> Result<()> {
+    fn write_command_raw(&mut self, command: &[u8; 16], data: &[u8]) -> Result<()> {
         // Issue CBW block
-        let cbw_data = &get_command_block_wrapper(command, data.len() as u32, Direction::OUT);
+        let tag = next_tag();
+        let cbw_data = &get_command_block_wrapper(command, tag, data.len() as u32, Direction::OUT)?;
         self.device_handle
             .write_bulk(self.endpoint_out, cbw_data, self.timeout)?;
 
         // Now write the data for the value
-        self.device_handle
-            .write_bulk(self.endpoint_out, &data, self.timeout)?;
+        let result = self
+            .device_handle
+            .write_bulk(self.endpoint_out, data, self.timeout);
+        let written = self.data_transferred(result, self.endpoint_out)?;
 
         // Issue CBS block
-        self.send_status_block_wrapper()?;
+        self.send_status_block_wrapper(tag)?;
+
+        if written != data.len() {
+            return Err(Error::DataResidue((data.len() - written) as u32));
+        }
 
         Ok(())
     }
+}
+
+impl Drop for ScsiOverUsbConnection {
+    fn drop(&mut self) {
+        if let Err(error) = self.device_handle.release_interface(self.interface_number) {
+            eprintln!("could not release usb interface: {}", error);
+        }
+    }
+}
+
+impl ScsiOverUsbConnection {
+    /// Open the given USB device and claim its interface.
+    ///
+    /// Every device can only be opened once at a time, but multiple devices can be used
+    /// simultaneously.
+    pub fn open(device: &UsbDevice) -> Result<Self> {
+        // Make sure you have the vendor whitelisted in your OS configuration aka
+        // /etc/udev/rules.d
+        let device_handle = device.device.open()?;
+        if let Err(e) = device_handle.set_auto_detach_kernel_driver(true) {
+            println!("auto detached failed, error is {}", e);
+        }
+        device_handle.claim_interface(INTERFACE)?;
 
-    fn send_status_block_wrapper(&mut self) -> Result<CommandStatusWrapper> {
-        let mut csb_data: [u8; 13] = [0; 13];
-        loop {
-            match self
+        Ok(Self {
+            device_handle,
+            interface_number: INTERFACE,
+            endpoint_out: ENDPOINT_OUT,
+            endpoint_in: ENDPOINT_IN,
+            timeout: Duration::from_millis(SCSI_TIMEOUT_MS),
+        })
+    }
+
+    /// Number of bytes moved by the data phase of a command. A stalled endpoint means the device
+    /// stopped the transfer early: Its halt gets cleared, so the status wrapper can be read and
+    /// tell what went wrong (Bulk-Only Transport, section 6.7).
+    fn data_transferred(&mut self, result: rusb::Result<usize>, endpoint: u8) -> Result<usize> {
+        match result {
+            Ok(size) => Ok(size),
+            Err(rusb::Error::Pipe) => {
+                self.device_handle.clear_halt(endpoint)?;
+                Ok(0)
+            }
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    fn send_status_block_wrapper(&mut self, tag: u32) -> Result<()> {
+        let mut csb_data: [u8; CSW_LENGTH] = [0; CSW_LENGTH];
+
+        // Try once more after clearing a stalled endpoint, give up when it stalls again
+        let mut result =
+            self.device_handle
+                .read_bulk(self.endpoint_in, &mut csb_data, self.timeout);
+        if let Err(rusb::Error::Pipe) = result {
+            self.device_handle.clear_halt(self.endpoint_in)?;
+            result = self
                 .device_handle
-                .read_bulk(self.endpoint_in, &mut csb_data, self.timeout)
-            {
-                Ok(_size) => {
-                    return Ok(bincode::options()
-                        .with_fixint_encoding()
-                        .deserialize::<CommandStatusWrapper>(&csb_data)
-                        .unwrap());
-                }
-                Err(error) => match error {
-                    Error::Pipe => {
-                        self.device_handle.clear_halt(self.endpoint_in).unwrap();
-                        continue;
-                    }
-                    _ => {
-                        return Err(error);
-                    }
-                },
+                .read_bulk(self.endpoint_in, &mut csb_data, self.timeout);
+        }
+
+        let size = match result {
+            Ok(size) => size,
+            Err(rusb::Error::Pipe) => {
+                self.reset_recovery()?;
+                return Err(Error::Phase);
             }
+            Err(error) => return Err(error.into()),
+        };
+
+        let status = check_status(&csb_data[..size], tag);
+
+        // A status wrapper we can not make sense of means that host and device are out of sync
+        if let Err(
+            Error::InvalidStatusLength(_)
+            | Error::InvalidSignature(_)
+            | Error::TagMismatch { .. }
+            | Error::Phase,
+        ) = status
+        {
+            self.reset_recovery()?;
         }
+
+        status
+    }
+
+    /// Perform Bulk-Only Transport reset recovery: Reset the mass storage interface and clear
+    /// the halt state of both bulk endpoints.
+    fn reset_recovery(&mut self) -> Result<()> {
+        self.device_handle.write_control(
+            BULK_ONLY_RESET_REQUEST_TYPE,
+            BULK_ONLY_RESET_REQUEST,
+            0,
+            self.interface_number as u16,
+            &[],
+            self.timeout,
+        )?;
+        self.device_handle.clear_halt(self.endpoint_in)?;
+        self.device_handle.clear_halt(self.endpoint_out)?;
+
+        Ok(())
     }
 }
 
+/// Check the status wrapper the device sent after the command with the given tag.
+fn check_status(data: &[u8], tag: u32) -> Result<()> {
+    if data.len() != CSW_LENGTH {
+        return Err(Error::InvalidStatusLength(data.len()));
+    }
+
+    let csw: CommandStatusWrapper = bincode::options()
+        .with_little_endian()
+        .with_fixint_encoding()
+        .deserialize(data)?;
+
+    if csw.signature != CSW_SIGNATURE {
+        return Err(Error::InvalidSignature(csw.signature));
+    }
+
+    if csw.tag != tag {
+        return Err(Error::TagMismatch {
+            expected: tag,
+            actual: csw.tag,
+        });
+    }
+
+    match csw.status {
+        CSW_STATUS_PASSED if csw.data_residue == 0 => Ok(()),
+        CSW_STATUS_PASSED => Err(Error::DataResidue(csw.data_residue)),
+        CSW_STATUS_FAILED => Err(Error::CommandFailed {
+            data_residue: csw.data_residue,
+        }),
+        // Phase error (0x02), reserved status values are treated the same way
+        _ => Err(Error::Phase),
+    }
+}
+
+fn next_tag() -> u32 {
+    TAG.fetch_add(1, Ordering::SeqCst)
+}
+
 fn get_command_block_wrapper(
     command_data: &[u8; 16],
+    tag: u32,
     data_transfer_length: u32,
     direction: Direction,
-) -> Vec<u8> {
+) -> Result<Vec<u8>> {
     let flags: u8 = match direction {
         Direction::IN => 0x80,
         Direction::OUT => 0x00,
     };
 
-    let tag = TAG.fetch_add(1, Ordering::SeqCst);
-
     let cwb = CommandBlockWrapper {
-        signature: [0x55, 0x53, 0x42, 0x43],
+        signature: CBW_SIGNATURE,
         tag,
         data_transfer_length,
         flags,
@@ -171,9 +419,124 @@ fn get_command_block_wrapper(
         command_data: *command_data,
     };
 
-    bincode::options()
+    let data = bincode::options()
         .with_little_endian()
         .with_fixint_encoding()
-        .serialize(&cwb)
-        .unwrap()
+        .serialize(&cwb)?;
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Status wrapper as the device would send it.
+    fn status(signature: [u8; 4], tag: u32, data_residue: u32, status: u8) -> Vec<u8> {
+        let csw = CommandStatusWrapper {
+            signature,
+            tag,
+            data_residue,
+            status,
+        };
+
+        bincode::options()
+            .with_little_endian()
+            .with_fixint_encoding()
+            .serialize(&csw)
+            .unwrap()
+    }
+
+    #[test]
+    fn accepts_passed_status() {
+        let data = status(CSW_SIGNATURE, 7, 0, CSW_STATUS_PASSED);
+
+        assert_eq!(data.len(), CSW_LENGTH);
+        assert!(check_status(&data, 7).is_ok());
+    }
+
+    #[test]
+    fn rejects_short_status() {
+        let data = status(CSW_SIGNATURE, 7, 0, CSW_STATUS_PASSED);
+
+        assert!(matches!(
+            check_status(&data[..12], 7),
+            Err(Error::InvalidStatusLength(12))
+        ));
+        assert!(matches!(
+            check_status(&[], 7),
+            Err(Error::InvalidStatusLength(0))
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_signature() {
+        let data = status(CBW_SIGNATURE, 7, 0, CSW_STATUS_PASSED);
+
+        assert!(matches!(
+            check_status(&data, 7),
+            Err(Error::InvalidSignature(signature)) if signature == CBW_SIGNATURE
+        ));
+    }
+
+    #[test]
+    fn rejects_tag_mismatch() {
+        let data = status(CSW_SIGNATURE, 8, 0, CSW_STATUS_PASSED);
+
+        assert!(matches!(
+            check_status(&data, 7),
+            Err(Error::TagMismatch {
+                expected: 7,
+                actual: 8
+            })
+        ));
+    }
+
+    #[test]
+    fn reports_phase_error() {
+        let data = status(CSW_SIGNATURE, 7, 0, 0x02);
+        assert!(matches!(check_status(&data, 7), Err(Error::Phase)));
+
+        // Reserved status values
+        let data = status(CSW_SIGNATURE, 7, 0, 0x03);
+        assert!(matches!(check_status(&data, 7), Err(Error::Phase)));
+    }
+
+    #[test]
+    fn reports_data_residue() {
+        let data = status(CSW_SIGNATURE, 7, 512, CSW_STATUS_PASSED);
+        assert!(matches!(
+            check_status(&data, 7),
+            Err(Error::DataResidue(512))
+        ));
+
+        let data = status(CSW_SIGNATURE, 7, 512, CSW_STATUS_FAILED);
+        assert!(matches!(
+            check_status(&data, 7),
+            Err(Error::CommandFailed { data_residue: 512 })
+        ));
+    }
+
+    #[test]
+    fn parses_device_selectors() {
+        assert_eq!(
+            "1:4".parse(),
+            Ok(DeviceSelector::BusAddress { bus: 1, address: 4 })
+        );
+        assert_eq!(
+            "001:004".parse(),
+            Ok(DeviceSelector::BusAddress { bus: 1, address: 4 })
+        );
+        assert_eq!(
+            "0123456789".parse(),
+            Ok(DeviceSelector::Serial("0123456789".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_bus_address() {
+        for value in ["1:300", "1:x", "x:4", ":4", "1:", ""] {
+            assert!(value.parse::<DeviceSelector>().is_err(), "{}", value);
+        }
+    }
 }
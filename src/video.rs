@@ -0,0 +1,427 @@
+use std::path::Path;
+use std::time::Duration;
+
+use ffmpeg_next::format::context::Input;
+use ffmpeg_next::format::stream::Stream;
+use ffmpeg_next::format::{input, Pixel};
+use ffmpeg_next::media::Type;
+use ffmpeg_next::software::scaling::{context::Context, flag::Flags};
+use ffmpeg_next::util::frame::video::Video;
+use ffmpeg_next::{Error, Rational};
+
+use it8951::dither::TemporalDither;
+use it8951::player::VideoFrame;
+
+use crate::filter::Filters;
+use crate::orientation::Orientation;
+use crate::scale::{Placement, Scaling};
+use crate::tone::{Tone, ToneMapper};
+
+/// Number of bins of the luminance histogram used to detect scene cuts.
+const HISTOGRAM_BINS: usize = 32;
+
+/// Rescaled 8bpp grayscale video frame, before it got dithered.
+pub struct GrayFrame {
+    /// Gray value of every pixel, row by row.
+    pub data: Vec<u8>,
+
+    /// Width of the frame in pixels.
+    pub width: u32,
+
+    /// Height of the frame in pixels.
+    pub height: u32,
+
+    /// Number of bytes of every row, can be larger than the width.
+    pub stride: usize,
+
+    /// Presentation time of the frame, relative to the beginning of the video.
+    pub timestamp: Duration,
+
+    /// Frame starts a new scene.
+    pub scene_cut: bool,
+}
+
+impl GrayFrame {
+    /// Dither the frame into the packed format of the given algorithm. Pixels are only kept
+    /// stable within a scene.
+    pub fn dither(&self, dither: &mut TemporalDither) -> VideoFrame {
+        if self.scene_cut {
+            dither.reset();
+        }
+
+        VideoFrame {
+            data: dither.dither(&self.data, self.width, self.height, self.stride),
+            timestamp: self.timestamp,
+            scene_cut: self.scene_cut,
+        }
+    }
+
+    /// Distribution of the gray values of all pixels, normalized so all bins add up to 1.
+    fn histogram(&self) -> [f32; HISTOGRAM_BINS] {
+        let mut histogram = [0.0; HISTOGRAM_BINS];
+        let weight = 1.0 / (self.width * self.height) as f32;
+
+        for row in self.data.chunks(self.stride).take(self.height as usize) {
+            for &value in &row[..self.width as usize] {
+                histogram[value as usize * HISTOGRAM_BINS / 256] += weight;
+            }
+        }
+
+        histogram
+    }
+}
+
+/// Detect scene cuts by comparing the luminance histograms of consecutive frames.
+///
+/// Unlike comparing the pixels themselves this is not affected by motion, only by the content
+/// changing completely.
+struct SceneDetector {
+    previous: Option<[f32; HISTOGRAM_BINS]>,
+    threshold: f32,
+}
+
+impl SceneDetector {
+    /// Consider frames a new scene when this share (0.0 to 1.0) of their histogram changed.
+    fn new(threshold: f32) -> Self {
+        Self {
+            previous: None,
+            threshold,
+        }
+    }
+
+    /// Returns true if the frame differs too much from the previous one. The first frame is
+    /// always a new scene.
+    fn is_scene_cut(&mut self, frame: &GrayFrame) -> bool {
+        let histogram = frame.histogram();
+
+        let scene_cut = match self.previous {
+            Some(previous) => {
+                let difference: f32 = previous
+                    .iter()
+                    .zip(&histogram)
+                    .map(|(a, b)| (a - b).abs())
+                    .sum();
+
+                // Every moved pixel is counted twice, once in each histogram
+                difference / 2.0 >= self.threshold
+            }
+            None => true,
+        };
+
+        self.previous = Some(histogram);
+        scene_cut
+    }
+}
+
+/// Settings how decoded frames get processed before they are dithered.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Processing {
+    /// Frames are tagged as scene cuts when this share (0.0 to 1.0) of their luminance histogram
+    /// changed.
+    pub scene_cut: f32,
+
+    /// How frames are scaled to the target dimensions.
+    pub scaling: Scaling,
+
+    /// How frames are turned as they should appear on the panel.
+    pub orientation: Orientation,
+
+    /// How gray values are adjusted.
+    pub tone: Tone,
+
+    /// Filters emphasizing details, applied after the gray values got adjusted.
+    pub filters: Filters,
+}
+
+/// Decode video file, rescale frames to target size and make them grayscale.
+pub struct VideoDecoder {
+    context: Input,
+    decoder: ffmpeg_next::decoder::Video,
+
+    /// Converts decoded frames into grayscale frames of their original size, only needed when a
+    /// part of them gets cropped before scaling.
+    converter: Option<Context>,
+
+    /// Scales (cropped) frames to their size in the target frame.
+    scaler: Context,
+
+    placement: Placement,
+    width: u32,
+    height: u32,
+    background: u8,
+
+    /// Turns scaled frames as they should appear on the panel.
+    orientation: Orientation,
+
+    /// Adjusts gray values of the scaled frames, before they get placed.
+    tone_mapper: ToneMapper,
+
+    filters: Filters,
+
+    video_stream_index: usize,
+    time_base: Rational,
+    frame_rate: Rational,
+    take: usize,
+    scene_detector: SceneDetector,
+    frame_counter: usize,
+    finished: bool,
+}
+
+impl VideoDecoder {
+    /// Open video stream from file, only taking every nth frame.
+    ///
+    /// Frames are scaled to the given dimensions, taking their sample aspect ratio into account,
+    /// and processed as defined by the settings.
+    pub fn open(
+        path: &Path,
+        width: u32,
+        height: u32,
+        take: usize,
+        processing: &Processing,
+    ) -> Result<Self, Error> {
+        let context = input(&path)?;
+
+        let stream = context
+            .streams()
+            .best(Type::Video)
+            .ok_or(Error::StreamNotFound)?;
+        let video_stream_index = stream.index();
+        let time_base = stream.time_base();
+        let frame_rate = stream_frame_rate(&stream)?;
+
+        // Prepare video decoder which should rescale frames to target size and make them grayscale
+        let context_decoder =
+            ffmpeg_next::codec::context::Context::from_parameters(stream.parameters())?;
+        let decoder = context_decoder.decoder().video()?;
+
+        // Pixels are square when the sample aspect ratio is unknown
+        let sample_aspect_ratio = decoder.aspect_ratio();
+        let sample_aspect_ratio =
+            if sample_aspect_ratio.numerator() > 0 && sample_aspect_ratio.denominator() > 0 {
+                f64::from(sample_aspect_ratio)
+            } else {
+                1.0
+            };
+
+        // Scale to the size the frames have before they get turned
+        let (width, height) = processing.orientation.source_size(width, height);
+        let placement = Placement::new(
+            decoder.width(),
+            decoder.height(),
+            sample_aspect_ratio,
+            width,
+            height,
+            &processing.scaling,
+        )
+        .ok_or(Error::InvalidData)?;
+
+        // Crop grayscale frames before scaling them when only a part of them is used
+        let (converter, scaler) = if placement.source.width == decoder.width()
+            && placement.source.height == decoder.height()
+        {
+            let scaler = Context::get(
+                decoder.format(),
+                decoder.width(),
+                decoder.height(),
+                Pixel::GRAY8,
+                placement.target.width,
+                placement.target.height,
+                processing.scaling.algorithm.flags(),
+            )?;
+            (None, scaler)
+        } else {
+            let converter = Context::get(
+                decoder.format(),
+                decoder.width(),
+                decoder.height(),
+                Pixel::GRAY8,
+                decoder.width(),
+                decoder.height(),
+                Flags::BILINEAR,
+            )?;
+            let scaler = Context::get(
+                Pixel::GRAY8,
+                placement.source.width,
+                placement.source.height,
+                Pixel::GRAY8,
+                placement.target.width,
+                placement.target.height,
+                processing.scaling.algorithm.flags(),
+            )?;
+            (Some(converter), scaler)
+        };
+
+        Ok(Self {
+            context,
+            decoder,
+            converter,
+            scaler,
+            placement,
+            width,
+            height,
+            background: processing.scaling.background,
+            orientation: processing.orientation,
+            tone_mapper: ToneMapper::new(processing.tone),
+            filters: processing.filters,
+            video_stream_index,
+            time_base,
+            frame_rate,
+            take,
+            scene_detector: SceneDetector::new(processing.scene_cut),
+            frame_counter: 0,
+            finished: false,
+        })
+    }
+
+    /// Frame rate of the video stream, never zero.
+    pub fn frame_rate(&self) -> Rational {
+        self.frame_rate
+    }
+
+    /// Read frame rate of the video stream in the file, without decoding anything.
+    pub fn probe_frame_rate(path: &Path) -> Result<Rational, Error> {
+        let context = input(&path)?;
+        let stream = context
+            .streams()
+            .best(Type::Video)
+            .ok_or(Error::StreamNotFound)?;
+        stream_frame_rate(&stream)
+    }
+
+    /// Decode the next packet of the video and pass every resulting frame to the callback.
+    ///
+    /// Returns false when the video ended and all remaining frames have been processed.
+    pub fn decode_next(&mut self, mut on_frame: impl FnMut(GrayFrame)) -> Result<bool, Error> {
+        if self.finished {
+            return Ok(false);
+        }
+
+        match self.context.packets().next() {
+            Some((stream, packet)) => {
+                if stream.index() == self.video_stream_index {
+                    self.decoder.send_packet(&packet)?;
+                    self.receive_and_process_decoded_frames(&mut on_frame)?;
+                }
+            }
+            None => {
+                self.decoder.send_eof()?;
+                self.receive_and_process_decoded_frames(&mut on_frame)?;
+                self.finished = true;
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn receive_and_process_decoded_frames(
+        &mut self,
+        on_frame: &mut impl FnMut(GrayFrame),
+    ) -> Result<(), Error> {
+        let mut decoded = Video::empty();
+
+        while self.decoder.receive_frame(&mut decoded).is_ok() {
+            // Only take every nth frame from video
+            if self.frame_counter % self.take == 0 {
+                // Rescale and convert to grayscale image
+                let mut scaled = Video::empty();
+                match &mut self.converter {
+                    Some(converter) => {
+                        let mut gray = Video::empty();
+                        converter.run(&decoded, &mut gray)?;
+                        self.scaler.run(&self.crop(&gray), &mut scaled)?;
+                    }
+                    None => self.scaler.run(&decoded, &mut scaled)?,
+                }
+
+                let mut frame = GrayFrame {
+                    data: scaled.data(0).to_vec(),
+                    width: scaled.width(),
+                    height: scaled.height(),
+                    stride: scaled.stride(0),
+                    timestamp: self.timestamp(&decoded),
+                    scene_cut: false,
+                };
+
+                // Only look at the video itself, without the background around it
+                frame.scene_cut = self.scene_detector.is_scene_cut(&frame);
+                self.tone_mapper.apply(&mut frame);
+                self.filters.apply(&mut frame);
+
+                on_frame(self.orientation.apply(self.place(frame)));
+            }
+
+            self.frame_counter += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Copy the part of the grayscale frame which is shown into a frame of its own.
+    fn crop(&self, gray: &Video) -> Video {
+        let source = self.placement.source;
+        let mut cropped = Video::new(Pixel::GRAY8, source.width, source.height);
+
+        let (stride, cropped_stride) = (gray.stride(0), cropped.stride(0));
+        let data = gray.data(0);
+        let cropped_data = cropped.data_mut(0);
+        for y in 0..source.height as usize {
+            let start = (source.y as usize + y) * stride + source.x as usize;
+            cropped_data[y * cropped_stride..y * cropped_stride + source.width as usize]
+                .copy_from_slice(&data[start..start + source.width as usize]);
+        }
+
+        cropped
+    }
+
+    /// Place the scaled grayscale frame in a frame of the target size, surrounded by the
+    /// background color.
+    fn place(&self, scaled: GrayFrame) -> GrayFrame {
+        let target = self.placement.target;
+
+        // Scaled frame covers everything already
+        if target.width == self.width && target.height == self.height {
+            return scaled;
+        }
+
+        let mut data = vec![self.background; (self.width * self.height) as usize];
+        for (y, row) in scaled
+            .data
+            .chunks(scaled.stride)
+            .take(target.height as usize)
+            .enumerate()
+        {
+            let start = (target.y as usize + y) * self.width as usize + target.x as usize;
+            data[start..start + target.width as usize]
+                .copy_from_slice(&row[..target.width as usize]);
+        }
+
+        GrayFrame {
+            data,
+            width: self.width,
+            height: self.height,
+            stride: self.width as usize,
+            ..scaled
+        }
+    }
+
+    /// Calculate presentation time of decoded frame via its timestamp and the time base of the
+    /// stream. Falls back to counting frames when no timestamp is given.
+    fn timestamp(&self, decoded: &Video) -> Duration {
+        let seconds = match decoded.timestamp() {
+            Some(timestamp) => timestamp as f64 * f64::from(self.time_base),
+            None => self.frame_counter as f64 / f64::from(self.frame_rate),
+        };
+
+        Duration::from_secs_f64(seconds.max(0.0))
+    }
+}
+
+/// Frame rate of the stream. Many streams do not report an average frame rate, the base frame rate
+/// (`r_frame_rate`) is used for them instead. Streams without either are rejected, their time base
+/// says nothing about the frame rate.
+fn stream_frame_rate(stream: &Stream) -> Result<Rational, Error> {
+    [stream.avg_frame_rate(), stream.rate()]
+        .into_iter()
+        .find(|rate| rate.numerator() > 0 && rate.denominator() > 0)
+        .ok_or(Error::InvalidData)
+}
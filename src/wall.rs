@@ -0,0 +1,433 @@
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+
+use anyhow::{Context, Result};
+use tokio::sync::broadcast;
+use tokio::sync::mpsc;
+use tokio::task;
+
+use it8951::dither::{BitmapFormat, Frame, TemporalDither};
+use it8951::ghost::GhostTracker;
+use it8951::player::{configure_panel, display_modes, image_size, memory_image};
+use it8951::ring::ImageBufferRing;
+use it8951::{DeviceSelector, Transport, UsbDevice, API};
+
+use crate::orientation::Rotation;
+use crate::video::{GrayFrame, VideoDecoder};
+use crate::WallOpt;
+
+/// Number of frames which can be queued up for every panel thread. This is kept small so all
+/// panels stop quickly after the video got cancelled.
+const TILE_BUFFER_SIZE: usize = 2;
+
+/// Position of one panel of the video wall.
+///
+/// Parsed from a comma separated list of settings, for example
+/// "device=1:4,column=1,row=0,rotate=90,x=-4,y=2".
+#[derive(Clone, PartialEq, Debug)]
+pub struct Tile {
+    /// Display showing this tile.
+    pub device: DeviceSelector,
+
+    /// Column of the tile in the grid of panels.
+    pub column: u32,
+
+    /// Row of the tile in the grid of panels.
+    pub row: u32,
+
+    /// Rotation of the image, to compensate for panels which are mounted rotated.
+    pub rotation: Rotation,
+
+    /// Horizontal offset of the tile on the canvas, to compensate for panels which are not
+    /// perfectly aligned.
+    pub offset_x: i32,
+
+    /// Vertical offset of the tile on the canvas.
+    pub offset_y: i32,
+}
+
+impl FromStr for Tile {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut tile = Tile {
+            device: DeviceSelector::Any,
+            column: 0,
+            row: 0,
+            rotation: Rotation::None,
+            offset_x: 0,
+            offset_y: 0,
+        };
+
+        for setting in value.split(',') {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("invalid tile setting '{}', use key=value", setting))?;
+            let invalid = |_| format!("invalid value '{}' for {}", value, key);
+
+            match key {
+                "device" => tile.device = value.parse()?,
+                "column" => tile.column = value.parse().map_err(invalid)?,
+                "row" => tile.row = value.parse().map_err(invalid)?,
+                "rotate" => tile.rotation = value.parse()?,
+                "x" => tile.offset_x = value.parse().map_err(invalid)?,
+                "y" => tile.offset_y = value.parse().map_err(invalid)?,
+                _ => return Err(format!("unknown tile setting '{}'", key)),
+            }
+        }
+
+        Ok(tile)
+    }
+}
+
+/// Arrangement of all panels on the combined canvas the video gets scaled to.
+pub struct Layout {
+    tile_width: u32,
+    tile_height: u32,
+    bezel_x: u32,
+    bezel_y: u32,
+    columns: u32,
+    rows: u32,
+}
+
+impl Layout {
+    /// Arrange tiles of the given size in a grid, with bezels of the given size in between.
+    pub fn new(
+        tiles: &[Tile],
+        tile_width: u32,
+        tile_height: u32,
+        bezel_x: u32,
+        bezel_y: u32,
+    ) -> Self {
+        Self {
+            tile_width,
+            tile_height,
+            bezel_x,
+            bezel_y,
+            columns: tiles.iter().map(|tile| tile.column + 1).max().unwrap_or(1),
+            rows: tiles.iter().map(|tile| tile.row + 1).max().unwrap_or(1),
+        }
+    }
+
+    /// Width of the whole canvas, including the hidden pixels behind the bezels.
+    pub fn canvas_width(&self) -> u32 {
+        self.columns * self.tile_width + (self.columns - 1) * self.bezel_x
+    }
+
+    /// Height of the whole canvas, including the hidden pixels behind the bezels.
+    pub fn canvas_height(&self) -> u32 {
+        self.rows * self.tile_height + (self.rows - 1) * self.bezel_y
+    }
+
+    /// Dimensions of the image displayed on the panel of the tile, after rotating it.
+    pub fn panel_size(&self, tile: &Tile) -> (u32, u32) {
+        match tile.rotation {
+            Rotation::None | Rotation::Clockwise180 => (self.tile_width, self.tile_height),
+            Rotation::Clockwise90 | Rotation::Clockwise270 => (self.tile_height, self.tile_width),
+        }
+    }
+
+    /// Cut the part of the tile out of the canvas and rotate it. Pixels outside of the canvas are
+    /// white.
+    pub fn crop(&self, frame: &GrayFrame, tile: &Tile) -> GrayFrame {
+        let (width, height) = self.panel_size(tile);
+        let left = (tile.column * (self.tile_width + self.bezel_x)) as i64 + tile.offset_x as i64;
+        let top = (tile.row * (self.tile_height + self.bezel_y)) as i64 + tile.offset_y as i64;
+        let (tile_width, tile_height) = (self.tile_width as i64, self.tile_height as i64);
+
+        let mut data = Vec::with_capacity((width * height) as usize);
+        for y in 0..height as i64 {
+            for x in 0..width as i64 {
+                // Find position of the pixel within the tile before it got rotated
+                let (tile_x, tile_y) = tile.rotation.source_position(x, y, tile_width, tile_height);
+
+                let (canvas_x, canvas_y) = (left + tile_x, top + tile_y);
+                let value = if canvas_x < 0
+                    || canvas_y < 0
+                    || canvas_x >= frame.width as i64
+                    || canvas_y >= frame.height as i64
+                {
+                    0xff
+                } else {
+                    frame.data[canvas_y as usize * frame.stride + canvas_x as usize]
+                };
+
+                data.push(value);
+            }
+        }
+
+        GrayFrame {
+            data,
+            width,
+            height,
+            stride: width as usize,
+            timestamp: frame.timestamp,
+            scene_cut: frame.scene_cut,
+        }
+    }
+}
+
+/// Lets all panels wait for each other, so every frame is displayed on all of them at the same
+/// time. Waiting is cancelled as soon as one of the panels failed.
+struct DisplaySync {
+    panels: usize,
+    state: Mutex<SyncState>,
+    condvar: Condvar,
+}
+
+struct SyncState {
+    /// Number of panels waiting for the others.
+    waiting: usize,
+
+    /// Increased every time all panels are ready.
+    generation: u64,
+
+    /// One of the panels failed, the others should not wait for it anymore.
+    aborted: bool,
+}
+
+impl DisplaySync {
+    fn new(panels: usize) -> Self {
+        Self {
+            panels,
+            state: Mutex::new(SyncState {
+                waiting: 0,
+                generation: 0,
+                aborted: false,
+            }),
+            condvar: Condvar::new(),
+        }
+    }
+
+    /// Block until all panels are ready. Returns false when waiting got aborted.
+    fn wait(&self) -> bool {
+        let mut state = self.state.lock().expect("sync state poisoned");
+        if state.aborted {
+            return false;
+        }
+
+        state.waiting += 1;
+        if state.waiting == self.panels {
+            state.waiting = 0;
+            state.generation += 1;
+            self.condvar.notify_all();
+            return true;
+        }
+
+        let generation = state.generation;
+        let state = self
+            .condvar
+            .wait_while(state, |state| {
+                state.generation == generation && !state.aborted
+            })
+            .expect("sync state poisoned");
+
+        state.generation != generation
+    }
+
+    /// Stop all panels from waiting for each other.
+    fn abort(&self) {
+        self.state.lock().expect("sync state poisoned").aborted = true;
+        self.condvar.notify_all();
+    }
+}
+
+/// Connect to the displays of all tiles. Tiles without a device selector get the first display
+/// which is not used by another tile.
+pub fn connect(tiles: &[Tile], layout: &Layout) -> Result<Vec<API>> {
+    let mut devices = UsbDevice::list()?;
+    let mut apis = Vec::with_capacity(tiles.len());
+
+    for (index, tile) in tiles.iter().enumerate() {
+        let position = devices
+            .iter()
+            .position(|device| device.matches(&tile.device))
+            .with_context(|| format!("No display found for tile {}", index))?;
+        let device = devices.remove(position);
+
+        let (width, height) = layout.panel_size(tile);
+        let api = API::connect_device(&device, width, height)
+            .with_context(|| format!("Failed connecting to display {}", device))?;
+        apis.push(api);
+    }
+
+    Ok(apis)
+}
+
+/// Decode the video once, split every frame into tiles and display them on all panels in sync.
+pub async fn play<T: Transport + Send + 'static>(apis: Vec<API<T>>, opt: WallOpt) -> Result<()> {
+    let layout = Layout::new(
+        &opt.tiles,
+        opt.video.width,
+        opt.video.height,
+        opt.bezel_x,
+        opt.bezel_y,
+    );
+    let depth = opt.video.bits_per_pixel;
+
+    println!(
+        r#"
+      VCOM value: {}
+          Panels: {} ({}x{})
+     Canvas Size: {}x{}
+       Tile Size: {}x{}
+  Bits per Pixel: {}
+        "#,
+        opt.vcom,
+        apis.len(),
+        layout.columns,
+        layout.rows,
+        layout.canvas_width(),
+        layout.canvas_height(),
+        opt.video.width,
+        opt.video.height,
+        depth.bits(),
+    );
+
+    let sync = Arc::new(DisplaySync::new(apis.len()));
+    let (shutdown_tx, mut shutdown_rx) = broadcast::channel::<bool>(1);
+
+    // Spawn one thread per panel: It will dither its tiles and display them as soon as all other
+    // panels are ready as well
+    let mut frame_txs = Vec::with_capacity(apis.len());
+    let mut panel_tasks = Vec::with_capacity(apis.len());
+    for (mut api, tile) in apis.into_iter().zip(&opt.tiles) {
+        let (frame_tx, frame_rx) = mpsc::channel::<GrayFrame>(TILE_BUFFER_SIZE);
+        frame_txs.push(frame_tx);
+
+        let (width, height) = layout.panel_size(tile);
+        let dither = opt.video.create_dither(depth);
+        let ghost = GhostTracker::new(width, height, depth, opt.ghost_threshold, opt.ghost);
+        let vcom = opt.vcom;
+        let bitmap = opt.bitmap.format();
+        let sync = sync.clone();
+
+        panel_tasks.push(task::spawn_blocking(move || -> Result<()> {
+            let result = configure_panel(&mut api, depth, width, vcom, &bitmap)
+                .map_err(anyhow::Error::from)
+                .and_then(|_| {
+                    let ring = ImageBufferRing::new(
+                        api.get_system_info(),
+                        image_size(depth, width, height),
+                    );
+                    display_tiles(&mut api, frame_rx, &sync, ring, dither, &bitmap, ghost)
+                });
+
+            // Do not let the other panels wait for this one anymore
+            if result.is_err() {
+                sync.abort();
+            }
+
+            result
+        }));
+    }
+
+    // Spawn the video thread: It will decode the video in the size of the whole canvas and send
+    // every tile over to its panel thread
+    let tiles = opt.tiles.clone();
+    let mut video_task = task::spawn_blocking(move || -> Result<()> {
+        let mut decoder = VideoDecoder::open(
+            &opt.input,
+            layout.canvas_width(),
+            layout.canvas_height(),
+            opt.video.take,
+            &opt.video.processing(),
+        )
+        .context("Failed opening video file")?;
+
+        // Decode packets until the video ended or we cancelled the process. Dropping the senders
+        // afterwards lets the panel threads finish
+        let mut cancelled = false;
+        while !cancelled {
+            if let Ok(true) = shutdown_rx.try_recv() {
+                break;
+            }
+
+            let decoding = decoder
+                .decode_next(|frame| {
+                    for (tile, frame_tx) in tiles.iter().zip(&frame_txs) {
+                        // Panel thread stopped when sending fails
+                        cancelled |= frame_tx.blocking_send(layout.crop(&frame, tile)).is_err();
+                    }
+                })
+                .context("Failed decoding video")?;
+
+            if !decoding {
+                break;
+            }
+        }
+
+        Ok(())
+    });
+
+    // Run this until [CTRL] + [C] got pressed, the video ended or something went wrong
+    let mut video_result = None;
+    tokio::select! {
+        result = &mut video_task => {
+            video_result = Some(result);
+        },
+        _ = tokio::signal::ctrl_c() => {
+            println!("\nExit program ..");
+        },
+    }
+
+    if shutdown_tx.send(true).is_err() {
+        // Ignore error
+    }
+
+    let video_result = match video_result {
+        Some(result) => result,
+        None => video_task.await,
+    };
+
+    // Wait until all panels got cleared and their controller registers restored
+    for panel_task in panel_tasks {
+        panel_task.await??;
+    }
+    video_result??;
+
+    Ok(())
+}
+
+/// Display all tiles received for this panel, in sync with the other panels.
+fn display_tiles<T: Transport>(
+    api: &mut API<T>,
+    mut frame_rx: mpsc::Receiver<GrayFrame>,
+    sync: &DisplaySync,
+    mut ring: ImageBufferRing,
+    mut dither: TemporalDither,
+    bitmap: &BitmapFormat,
+    mut ghost: GhostTracker,
+) -> Result<()> {
+    let depth = dither.depth();
+    let (fast_mode, clean_mode) = display_modes(depth);
+    let mut displayed: Option<Frame> = None;
+
+    while let Some(frame) = frame_rx.blocking_recv() {
+        let frame = frame.dither(&mut dither);
+
+        let address = ring.next_address();
+        api.set_memory(address, &memory_image(&frame.data, depth, bitmap))?;
+
+        // Wait until all panels uploaded their tile of this frame
+        if !sync.wait() {
+            break;
+        }
+
+        // Sometimes draw image properly (this is slower) to avoid too much ghosting. Every panel
+        // tracks the transitions of its own tile, the slower cleanups of one panel only delay the
+        // others until the next frame
+        if ghost.next_frame(displayed.as_deref(), &frame.data, frame.scene_cut) {
+            api.display_image(address, clean_mode)?;
+        } else {
+            // ... and display the others with a faster mode
+            api.display_image(address, fast_mode)?;
+        }
+
+        displayed = Some(frame.data);
+    }
+
+    api.clear_display()?;
+
+    Ok(())
+}